@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{ffi::OsString, path::PathBuf};
 
 #[derive(clap::Parser)]
 struct LitWebCli {
@@ -9,6 +9,14 @@ struct LitWebCli {
 #[derive(clap::Subcommand)]
 enum LitWebSubcmd {
     Tangle(LitWebTangleArgs),
+    Weave(LitWebWeaveArgs),
+    /// Run cargo, pointing its diagnostics at the literate sources
+    Cargo(LitWebCargoArgs),
+    /// Find where a line of a generated file comes from, or where a line of
+    /// a .lit.djot document ends up
+    Locate(LitWebLocateArgs),
+    /// Build and run a .lit.djot document as a script
+    Run(LitWebRunArgs),
 }
 
 #[derive(clap::Parser)]
@@ -18,6 +26,80 @@ struct LitWebTangleArgs {
     output: Option<PathBuf>,
     #[arg(long, short)]
     force: bool,
+    /// Mark every code block in the generated file with its source block
+    #[arg(long)]
+    block_markers: bool,
+    /// Generate only the expansion of the named chunk
+    #[arg(long = "root", short = 'R')]
+    root_chunk: Option<String>,
+    /// Write code blocks of another language to the given path, as LANG=PATH
+    #[arg(long = "output-for", value_parser = parse_language_output)]
+    language_outputs: Vec<(String, PathBuf)>,
+    /// Wrap code under headings with a `mod` attribute in modules
+    #[arg(long)]
+    modules: bool,
+    /// Write prose as rustdoc comments attached to the code that follows it
+    #[arg(long)]
+    rustdoc: bool,
+    /// How to print errors that point into the document or generated file
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Human)]
+    error_format: ErrorFormat,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+fn parse_language_output(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((lang, path)) if !lang.is_empty() && !path.is_empty() => {
+            Ok((lang.to_owned(), PathBuf::from(path)))
+        }
+        _ => Err(format!("expected LANG=PATH, found `{value}`")),
+    }
+}
+
+#[derive(clap::Parser)]
+struct LitWebWeaveArgs {
+    input: PathBuf,
+    #[arg(short = 'O')]
+    output: Option<PathBuf>,
+    #[arg(long, short)]
+    force: bool,
+    /// Run Rust blocks marked `eval` and add their output below them
+    #[arg(long)]
+    eval: bool,
+    /// Where to build evaluated blocks and cache their output
+    #[arg(long, requires = "eval")]
+    scratch_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Parser)]
+struct LitWebCargoArgs {
+    /// Print cargo's JSON messages instead of rendered diagnostics
+    #[arg(long)]
+    json: bool,
+    /// The cargo command and its arguments, e.g. `check --all-targets`
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<OsString>,
+}
+
+#[derive(clap::Parser)]
+struct LitWebLocateArgs {
+    /// A generated file or a .lit.djot document
+    file: PathBuf,
+    /// A 1-based line number in that file
+    line: usize,
+}
+
+#[derive(clap::Parser)]
+struct LitWebRunArgs {
+    input: PathBuf,
+    /// Arguments passed on to the script
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<OsString>,
 }
 
 fn main() {
@@ -25,13 +107,108 @@ fn main() {
     let cli = LitWebCli::parse();
     match cli.subcommand {
         LitWebSubcmd::Tangle(tangle_args) => {
-            if let Err(err) = litweb::tangle_or_untangle(
+            let options = litweb::TangleOptions {
+                block_markers: tangle_args.block_markers,
+                root_chunk: tangle_args.root_chunk,
+                language_outputs: tangle_args.language_outputs,
+                modules: tangle_args.modules,
+                rustdoc: tangle_args.rustdoc,
+            };
+            match litweb::tangle_or_untangle(
                 &tangle_args.input,
                 tangle_args.output.as_deref(),
                 tangle_args.force,
+                &options,
+            ) {
+                Ok(produced) => {
+                    for path in produced {
+                        println!("{}", path.display());
+                    }
+                }
+                Err(litweb::TangleUntangleError::Located(diagnostic)) => {
+                    match tangle_args.error_format {
+                        ErrorFormat::Human => eprint!("{}", diagnostic.render()),
+                        ErrorFormat::Json => println!("{}", diagnostic.to_json()),
+                    }
+                }
+                Err(err) => eprintln!("ERROR: {err}"),
+            }
+        }
+        LitWebSubcmd::Weave(weave_args) => {
+            let options = litweb::WeaveOptions {
+                eval: weave_args.eval,
+                scratch_dir: weave_args.scratch_dir,
+            };
+            if let Err(err) = litweb::weave_or_unweave(
+                &weave_args.input,
+                weave_args.output.as_deref(),
+                weave_args.force,
+                &options,
             ) {
                 eprintln!("ERROR: {err}");
             }
         }
+        LitWebSubcmd::Cargo(cargo_args) => {
+            let format = if cargo_args.json {
+                litweb::MessageFormat::Json
+            } else {
+                litweb::MessageFormat::Human
+            };
+            match litweb::run_cargo(&cargo_args.args, format) {
+                Ok(run) => {
+                    for warning in run.warnings {
+                        eprintln!("warning: {warning}");
+                    }
+                    std::process::exit(run.status.code().unwrap_or(1))
+                }
+                Err(err) => eprintln!("ERROR: {err}"),
+            }
+        }
+        LitWebSubcmd::Locate(locate_args) => {
+            let is_source = locate_args
+                .file
+                .to_str()
+                .is_some_and(|path| path.ends_with(".lit.djot"));
+            let located = if is_source {
+                litweb::locate_in_generated(&locate_args.file, locate_args.line)
+            } else {
+                litweb::locate_in_source(&locate_args.file, locate_args.line)
+                    .map(|location| vec![location])
+            };
+            match located {
+                Ok(locations) => {
+                    for location in locations {
+                        println!(
+                            "{}:{}:{}",
+                            location.file.display(),
+                            location.line,
+                            location.column
+                        );
+                    }
+                }
+                Err(err) => eprintln!("ERROR: {err}"),
+            }
+        }
+        LitWebSubcmd::Run(run_args) => match litweb::run_script(&run_args.input, &run_args.args) {
+            Ok(run) => {
+                for warning in run.warnings {
+                    eprintln!("warning: {warning}");
+                }
+                std::process::exit(run.status.code().unwrap_or(1))
+            }
+            Err(litweb::RunError::Tangle(litweb::TangleUntangleError::Located(diagnostic))) => {
+                eprint!("{}", diagnostic.render());
+                std::process::exit(1);
+            }
+            Err(err) => {
+                if let litweb::RunError::BuildFailed(build) = &err {
+                    for warning in &build.warnings {
+                        eprintln!("warning: {warning}");
+                    }
+                }
+                eprintln!("ERROR: {err}");
+                std::process::exit(1);
+            }
+        },
     }
 }
@@ -0,0 +1,437 @@
+/// How `run_cargo` reports diagnostics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Rendered like the compiler does, on stderr.
+    Human,
+    /// Cargo's JSON messages on stdout, as with `--message-format=json`.
+    Json,
+}
+
+/// How a cargo run went.
+#[derive(Debug)]
+pub struct CargoRun {
+    pub status: ExitStatus,
+    /// Generated files whose spans were left as they are, because they are
+    /// out of sync with their source.
+    pub warnings: Vec<TangleUntangleError>,
+}
+
+#[derive(Error, Debug)]
+pub enum CargoError {
+    #[error("Could not find the cargo workspace root")]
+    NoWorkspaceRoot,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Runs cargo with the given arguments, e.g. `["check"]`, and reports its
+/// diagnostics with every span in a litweb-generated file moved to the
+/// `.lit.djot` document it was tangled from.
+///
+/// Generated files that are out of sync with their source are reported
+/// once in the returned warnings, and their spans are left as they are.
+pub fn run_cargo(args: &[OsString], format: MessageFormat) -> Result<CargoRun, CargoError> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let workspace_root = locate_workspace_root(&cargo)?;
+    run_cargo_in(args, format, workspace_root)
+}
+
+/// Like `run_cargo`, for the workspace at `workspace_root`, which the paths
+/// in cargo's messages are relative to.
+pub(crate) fn run_cargo_in(
+    args: &[OsString],
+    format: MessageFormat,
+    workspace_root: PathBuf,
+) -> Result<CargoRun, CargoError> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    // Arguments after `--` are for the program cargo runs, such as a test
+    // binary, so the format goes before them.
+    let cargo_args_len = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    let mut child = Command::new(&cargo)
+        .args(&args[..cargo_args_len])
+        .arg("--message-format=json")
+        .args(&args[cargo_args_len..])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut remapper = SpanRemapper::new(workspace_root);
+    let forwarded = forward_messages(child.stdout.take().unwrap(), format, &mut remapper);
+    // Cargo is waited for even if forwarding failed, so that it does not
+    // outlive the call.
+    let status = child.wait()?;
+    forwarded?;
+    Ok(CargoRun {
+        status,
+        warnings: remapper.warnings,
+    })
+}
+
+/// Passes cargo's messages on to stdout, or to stderr for rendered
+/// diagnostics, with their spans pointing into the literate sources.
+fn forward_messages(
+    cargo_stdout: impl io::Read,
+    format: MessageFormat,
+    remapper: &mut SpanRemapper,
+) -> Result<(), CargoError> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for line in BufReader::new(cargo_stdout).lines() {
+        let line = line?;
+        // Anything that is not a cargo message, such as the output of test
+        // binaries, is passed through as is.
+        let Ok(mut message) = serde_json::from_str::<Value>(&line) else {
+            writeln!(stdout, "{line}")?;
+            continue;
+        };
+        if message["reason"] != "compiler-message" {
+            if format == MessageFormat::Json {
+                writeln!(stdout, "{line}")?;
+            }
+            continue;
+        }
+        let Ok(mut diagnostic) = serde_json::from_value::<Diagnostic>(message["message"].take())
+        else {
+            writeln!(stdout, "{line}")?;
+            continue;
+        };
+        if remapper.remap(&mut diagnostic) {
+            diagnostic.rendered = Some(render(&diagnostic));
+        }
+        match format {
+            MessageFormat::Human => {
+                if let Some(rendered) = &diagnostic.rendered {
+                    eprint!("{rendered}");
+                }
+            }
+            MessageFormat::Json => {
+                message["message"] = serde_json::to_value(diagnostic).map_err(io::Error::from)?;
+                writeln!(stdout, "{message}")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn locate_workspace_root(cargo: &OsStr) -> Result<PathBuf, CargoError> {
+    let output = Command::new(cargo)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(CargoError::NoWorkspaceRoot);
+    }
+    let manifest = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    match manifest.parent() {
+        Some(root) => Ok(fs::canonicalize(root)?),
+        None => Err(CargoError::NoWorkspaceRoot),
+    }
+}
+
+/// A compiler diagnostic as found in cargo's JSON messages. Fields that are
+/// not needed for remapping are carried along untouched.
+#[derive(Serialize, Deserialize)]
+struct Diagnostic {
+    message: String,
+    code: Option<DiagnosticCode>,
+    level: String,
+    spans: Vec<DiagnosticSpan>,
+    children: Vec<Diagnostic>,
+    rendered: Option<String>,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiagnosticCode {
+    code: String,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiagnosticSpan {
+    file_name: String,
+    byte_start: usize,
+    byte_end: usize,
+    line_start: usize,
+    line_end: usize,
+    column_start: usize,
+    column_end: usize,
+    is_primary: bool,
+    text: Vec<DiagnosticSpanLine>,
+    label: Option<String>,
+    expansion: Option<Box<DiagnosticExpansion>>,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiagnosticSpanLine {
+    text: String,
+    highlight_start: usize,
+    highlight_end: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiagnosticExpansion {
+    span: DiagnosticSpan,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+/// A generated file's source map along with the text of its source.
+struct MappedFile {
+    source_map: SourceMap,
+    source_name: String,
+    source_lines: Vec<String>,
+}
+
+/// Moves diagnostic spans from generated files to their sources, loading
+/// each file's source map once.
+struct SpanRemapper {
+    workspace_root: PathBuf,
+    files: HashMap<String, Option<MappedFile>>,
+    warnings: Vec<TangleUntangleError>,
+}
+
+impl SpanRemapper {
+    fn new(workspace_root: PathBuf) -> Self {
+        SpanRemapper {
+            workspace_root,
+            files: HashMap::new(),
+            warnings: vec![],
+        }
+    }
+
+    /// Remaps every span of the diagnostic and its children, returning
+    /// whether any was moved.
+    fn remap(&mut self, diagnostic: &mut Diagnostic) -> bool {
+        let mut remapped = false;
+        for span in &mut diagnostic.spans {
+            remapped |= self.remap_span(span);
+        }
+        for child in &mut diagnostic.children {
+            remapped |= self.remap(child);
+        }
+        remapped
+    }
+
+    fn remap_span(&mut self, span: &mut DiagnosticSpan) -> bool {
+        let mut remapped = false;
+        if let Some(expansion) = &mut span.expansion {
+            remapped |= self.remap_span(&mut expansion.span);
+        }
+        let Some(file) = self.mapped_file(&span.file_name) else {
+            return remapped;
+        };
+        let Some(start) = file
+            .source_map
+            .lookup_position(span.line_start, span.column_start)
+        else {
+            return remapped;
+        };
+        // A span may run past the lines of a single code block, in which case
+        // it is cut short at the end of its first source line.
+        let end = file
+            .source_map
+            .lookup_position(span.line_end, span.column_end)
+            .filter(|end| (end.line, end.column) >= (start.line, start.column))
+            .unwrap_or_else(|| SourceLocation {
+                column: file.line_width(start.line) + 1,
+                ..start.clone()
+            });
+        span.file_name = file.source_name.clone();
+        span.line_start = start.line;
+        span.column_start = start.column;
+        span.line_end = end.line;
+        span.column_end = end.column;
+        span.byte_start = file.byte_offset(start.line, start.column);
+        span.byte_end = file.byte_offset(end.line, end.column);
+        span.text = (start.line..=end.line)
+            .map(|line| DiagnosticSpanLine {
+                text: file.line_text(line).to_owned(),
+                highlight_start: if line == start.line { start.column } else { 1 },
+                highlight_end: if line == end.line {
+                    end.column
+                } else {
+                    file.line_width(line) + 1
+                },
+            })
+            .collect();
+        true
+    }
+
+    fn mapped_file(&mut self, file_name: &str) -> Option<&MappedFile> {
+        if !self.files.contains_key(file_name) {
+            let mapped_file = self.load(file_name);
+            self.files.insert(file_name.to_owned(), mapped_file);
+        }
+        self.files[file_name].as_ref()
+    }
+
+    fn load(&mut self, file_name: &str) -> Option<MappedFile> {
+        let source_map = match crate::load_source_map(&self.workspace_root.join(file_name)) {
+            Ok(source_map) => source_map,
+            Err(err @ TangleUntangleError::OutOfSync { .. }) => {
+                self.warnings.push(err);
+                return None;
+            }
+            Err(_) => return None,
+        };
+        let source_path = fs::canonicalize(source_map.source()).ok()?;
+        let source_text = fs::read_to_string(&source_path).ok()?;
+        // Paths are reported relative to the workspace root, like the
+        // compiler's own.
+        let source_name = source_path
+            .strip_prefix(&self.workspace_root)
+            .unwrap_or(&source_path)
+            .display()
+            .to_string();
+        Some(MappedFile {
+            source_map,
+            source_name,
+            source_lines: source_text.split('\n').map(str::to_owned).collect(),
+        })
+    }
+}
+
+impl MappedFile {
+    fn line_text(&self, line: usize) -> &str {
+        self.source_lines
+            .get(line - 1)
+            .map_or("", |text| text.trim_end_matches('\r'))
+    }
+
+    fn line_width(&self, line: usize) -> usize {
+        self.line_text(line).chars().count()
+    }
+
+    fn byte_offset(&self, line: usize, column: usize) -> usize {
+        let line_start: usize = self
+            .source_lines
+            .iter()
+            .take(line - 1)
+            .map(|text| text.len() + 1)
+            .sum();
+        let text = self.line_text(line);
+        line_start
+            + text
+                .char_indices()
+                .nth(column - 1)
+                .map_or(text.len(), |(offset, _)| offset)
+    }
+}
+
+/// Renders a diagnostic the way the compiler does, without colors.
+fn render(diagnostic: &Diagnostic) -> String {
+    let mut rendered = String::new();
+    render_into(diagnostic, false, &mut rendered);
+    rendered
+}
+
+fn render_into(diagnostic: &Diagnostic, is_child: bool, rendered: &mut String) {
+    let gutter_width = diagnostic
+        .spans
+        .iter()
+        .map(|span| span.line_start.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let gutter = " ".repeat(gutter_width);
+    if is_child && diagnostic.spans.is_empty() {
+        let _ = writeln!(
+            rendered,
+            "{gutter} = {}: {}",
+            diagnostic.level, diagnostic.message
+        );
+        return;
+    }
+    match &diagnostic.code {
+        Some(code) => {
+            let _ = writeln!(
+                rendered,
+                "{}[{}]: {}",
+                diagnostic.level, code.code, diagnostic.message
+            );
+        }
+        None => {
+            let _ = writeln!(rendered, "{}: {}", diagnostic.level, diagnostic.message);
+        }
+    }
+    let primary = diagnostic
+        .spans
+        .iter()
+        .find(|span| span.is_primary)
+        .or(diagnostic.spans.first());
+    if let Some(primary) = primary {
+        let _ = writeln!(
+            rendered,
+            "{gutter}--> {}:{}:{}",
+            primary.file_name, primary.line_start, primary.column_start
+        );
+        let _ = writeln!(rendered, "{gutter} |");
+        let mut previous_line = None;
+        for span in &diagnostic.spans {
+            let Some(line) = span.text.first() else {
+                continue;
+            };
+            let marker = if span.is_primary { "^" } else { "-" };
+            let underline_start = line.highlight_start.saturating_sub(1);
+            let underline_width = line
+                .highlight_end
+                .saturating_sub(line.highlight_start)
+                .max(1);
+            // Spans on the same line share one copy of it.
+            let location = (&span.file_name, span.line_start);
+            if previous_line.replace(location) != Some(location) {
+                let _ = writeln!(
+                    rendered,
+                    "{:>gutter_width$} | {}",
+                    span.line_start, line.text
+                );
+            }
+            let _ = write!(
+                rendered,
+                "{gutter} | {}{}",
+                " ".repeat(underline_start),
+                marker.repeat(underline_width)
+            );
+            match &span.label {
+                Some(label) => {
+                    let _ = writeln!(rendered, " {label}");
+                }
+                None => rendered.push('\n'),
+            }
+        }
+        let _ = writeln!(rendered, "{gutter} |");
+    }
+    for child in &diagnostic.children {
+        render_into(child, true, rendered);
+    }
+    if !is_child {
+        rendered.push('\n');
+    }
+}
+
+use crate::source_map::{SourceLocation, SourceMap};
+use crate::TangleUntangleError;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use thiserror::Error;
@@ -0,0 +1,145 @@
+/// An error pinned to a line and column of the file it was found in.
+/// Lines and columns are 1-based, and columns count characters.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    /// The text of the line the error points at.
+    pub snippet: String,
+    pub error: TangleUntangleError,
+}
+
+impl Diagnostic {
+    /// Renders the error the way the compiler does, with the offending line
+    /// and a caret under the column.
+    pub fn render(&self) -> String {
+        let line_number = self.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let caret_indent = " ".repeat(self.column.saturating_sub(1));
+        format!(
+            "error: {error}\n\
+             {gutter}--> {path}:{line}:{column}\n\
+             {gutter} |\n\
+             {line_number} | {snippet}\n\
+             {gutter} | {caret_indent}^\n",
+            error = self.error,
+            path = self.path.display(),
+            line = self.line,
+            column = self.column,
+            snippet = self.snippet,
+        )
+    }
+
+    /// Renders the error as a single line of JSON, for editors.
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "message": self.error.to_string(),
+            "file": self.path,
+            "line": self.line,
+            "column": self.column,
+            "snippet": self.snippet,
+        })
+        .to_string()
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.path.display(),
+            self.line,
+            self.column,
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for Diagnostic {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl TangleUntangleError {
+    fn position(&self) -> Option<ErrorPosition> {
+        match self {
+            TangleUntangleError::TangleUntangleAnalysisError(err) => err.position(),
+            TangleUntangleError::TangleUntangleConversionError(err) => err.position(),
+            _ => None,
+        }
+    }
+}
+
+/// Pins an error that knows where it was found to the file it concerns,
+/// leaving other errors as they are.
+pub(crate) fn locate(
+    error: TangleUntangleError,
+    source_path: &Path,
+    generated_path: &Path,
+) -> TangleUntangleError {
+    let Some(position) = error.position() else {
+        return error;
+    };
+    let path = match position {
+        ErrorPosition::SourceOffset(_)
+        | ErrorPosition::SourceLine(_)
+        | ErrorPosition::SourceColumn(..) => source_path,
+        ErrorPosition::GeneratedLine(_) => generated_path,
+    };
+    let Ok(text) = fs::read_to_string(path) else {
+        return error;
+    };
+    let (line, column) = match position {
+        ErrorPosition::SourceOffset(offset) => {
+            let offset = offset.min(text.len());
+            let line_start = text[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+            (
+                text[..offset].matches('\n').count() + 1,
+                text[line_start..offset].chars().count() + 1,
+            )
+        }
+        ErrorPosition::SourceColumn(line, column) => (line, column),
+        // Point at where the text of the line starts.
+        ErrorPosition::SourceLine(line) | ErrorPosition::GeneratedLine(line) => {
+            let indent = text.lines().nth(line - 1).map_or(0, |text| {
+                text.chars().take_while(|c| c.is_whitespace()).count()
+            });
+            (line, indent + 1)
+        }
+    };
+    let snippet = text.lines().nth(line - 1).unwrap_or("").to_owned();
+    TangleUntangleError::Located(Box::new(Diagnostic {
+        path: path.to_owned(),
+        line,
+        column,
+        snippet,
+        error,
+    }))
+}
+
+use crate::tangle_and_untangle::ErrorPosition;
+use crate::TangleUntangleError;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn column_zero_renders_at_line_start() {
+        let diagnostic = Diagnostic {
+            path: PathBuf::from("doc.lit.djot"),
+            line: 3,
+            column: 0,
+            snippet: "fn main() {}".to_owned(),
+            error: TangleUntangleError::UnrecognizedFileExt,
+        };
+        assert!(diagnostic.render().ends_with("  | ^\n"));
+    }
+}
@@ -0,0 +1,139 @@
+/// Compiles and runs the Rust code blocks marked `eval` while weaving, each
+/// as the `main.rs` of a scratch crate.
+///
+/// A block is wrapped in a `main` function unless it defines one itself.
+/// The output of every program that ran successfully is cached under the
+/// hash of its text, so weaving a document again only runs the blocks that
+/// changed.
+pub(crate) struct Evaluator {
+    scratch_dir: PathBuf,
+    source_name: String,
+}
+
+/// What an evaluated block printed.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Evaluation {
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+}
+
+#[derive(Error, Debug)]
+pub enum EvalError {
+    #[error("Block at {location} does not compile:\n{stderr}")]
+    CompileFailed { location: String, stderr: String },
+    #[error("Block at {location} failed when run ({status}):\n{stderr}")]
+    RunFailed {
+        location: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+const SCRATCH_MANIFEST: &str = "\
+[package]
+name = \"litweb-eval\"
+version = \"0.0.0\"
+edition = \"2021\"
+publish = false
+
+[workspace]
+";
+
+impl Evaluator {
+    /// Evaluates the blocks of the document named `source_name` in the
+    /// scratch crate at `scratch_dir`, which is created as needed.
+    pub(crate) fn new(scratch_dir: PathBuf, source_name: String) -> Self {
+        Evaluator {
+            scratch_dir,
+            source_name,
+        }
+    }
+
+    /// Runs `code`, the contents of the block whose opening fence is on
+    /// `line` of the source.
+    pub(crate) fn evaluate(&mut self, code: &str, line: usize) -> Result<Evaluation, EvalError> {
+        let program = if code.contains("fn main(") {
+            format!("#![allow(unused)]\n{code}")
+        } else {
+            format!("#![allow(unused)]\nfn main() {{\n{code}}}\n")
+        };
+        let results_dir = self.scratch_dir.join("results");
+        let result_path = results_dir.join(format!("{:016x}.json", utils::content_hash(&program)));
+        if let Some(evaluation) = fs::read_to_string(&result_path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+        {
+            return Ok(evaluation);
+        }
+        let location = format!("{}:{line}", self.source_name);
+        fs::create_dir_all(self.scratch_dir.join("src"))?;
+        fs::write(self.scratch_dir.join("Cargo.toml"), SCRATCH_MANIFEST)?;
+        fs::write(self.scratch_dir.join("src").join("main.rs"), &program)?;
+        let target_dir = self.scratch_dir.join("target");
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let build = Command::new(cargo)
+            .args(["build", "--quiet", "--offline", "--manifest-path"])
+            .arg(self.scratch_dir.join("Cargo.toml"))
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .output()?;
+        if !build.status.success() {
+            return Err(EvalError::CompileFailed {
+                location,
+                stderr: String::from_utf8_lossy(&build.stderr).into_owned(),
+            });
+        }
+        let binary = target_dir
+            .join("debug")
+            .join(format!("litweb-eval{}", env::consts::EXE_SUFFIX));
+        let run = Command::new(binary).stdin(Stdio::null()).output()?;
+        let evaluation = Evaluation {
+            stdout: String::from_utf8_lossy(&run.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&run.stderr).into_owned(),
+        };
+        if !run.status.success() {
+            return Err(EvalError::RunFailed {
+                location,
+                status: run.status,
+                stderr: evaluation.stderr,
+            });
+        }
+        fs::create_dir_all(&results_dir)?;
+        fs::write(
+            &result_path,
+            serde_json::to_string(&evaluation).map_err(io::Error::from)?,
+        )?;
+        Ok(evaluation)
+    }
+}
+
+/// Whether a code block is to be evaluated, i.e. is Rust and carries an
+/// `eval` class or attribute.
+pub(crate) fn is_evaluated(language: &str, attributes: &DjotAttributes) -> bool {
+    let is_rust = GeneratedLanguage::from_code_block_tag(language) == Some(GeneratedLanguage::Rust);
+    let is_marked = attributes.get("eval").is_some()
+        || attributes.get("class").is_some_and(|classes| {
+            classes
+                .to_string()
+                .split_whitespace()
+                .any(|class| class == "eval")
+        });
+    is_rust && is_marked
+}
+
+use crate::tangle_and_untangle::GeneratedLanguage;
+use crate::utils;
+use serde::Deserialize;
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use thiserror::Error;
+
+use jotdown::Attributes as DjotAttributes;
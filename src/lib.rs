@@ -1,17 +1,23 @@
-use std::{fs::File, io, path::Path};
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
 use thiserror::Error;
 
 enum FileType {
     SourceDjot,
     GeneratedMarkdown,
-    GeneratedRustModule,
+    GeneratedModule(GeneratedLanguage),
 }
 
 #[macro_use]
 mod utils {
-    use crate::{FileType, TangleOrWeave};
+    use crate::{tangle_and_untangle::GeneratedLanguage, FileType, TangleOrWeave};
     use std::{
-        fs, io,
+        env, fs, io,
         path::{Path, PathBuf},
         time::SystemTime,
     };
@@ -35,14 +41,19 @@ mod utils {
     ) -> Option<(FileType, PathBuf, FileType)> {
         if input.extension_eq("djot") && input.with_extension("").extension_eq("lit") {
             let (dest_ext, dest_type) = match mode {
-                TangleOrWeave::Tangle => ("rs", FileType::GeneratedRustModule),
+                TangleOrWeave::Tangle => ("rs", FileType::GeneratedModule(GeneratedLanguage::Rust)),
                 TangleOrWeave::Weave => ("md", FileType::GeneratedMarkdown),
             };
             let dest = input.with_extension("").with_extension(dest_ext);
             Some((FileType::SourceDjot, dest, dest_type))
-        } else if matches!(mode, TangleOrWeave::Tangle) && input.extension_eq("rs") {
+        } else if let Some(lang) = input
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(GeneratedLanguage::from_extension)
+            .filter(|_| matches!(mode, TangleOrWeave::Tangle))
+        {
             Some((
-                FileType::GeneratedRustModule,
+                FileType::GeneratedModule(lang),
                 input.with_extension("lit.djot"),
                 FileType::SourceDjot,
             ))
@@ -63,7 +74,8 @@ mod utils {
         force: bool,
     ) -> Result<Option<SystemTime>, io::Error> {
         let input_modified = fs::metadata(input)?.modified()?;
-        let Some(output_modified) = fs::metadata(output).ok().and_then(|m| m.modified().ok()) else {
+        let Some(output_modified) = fs::metadata(output).ok().and_then(|m| m.modified().ok())
+        else {
             return Ok(Some(input_modified));
         };
         if input_modified > output_modified || force {
@@ -72,6 +84,80 @@ mod utils {
         Ok(None)
     }
 
+    /// Hashes text with 64-bit FNV-1a, which unlike `DefaultHasher` is
+    /// guaranteed to stay the same across Rust releases, so the result can be
+    /// persisted in generated files.
+    pub(crate) fn content_hash(text: impl AsRef<[u8]>) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+        text.as_ref().iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+        })
+    }
+
+    /// The path that leads from `dir` to `path`, for recording one file's
+    /// location in another that may be moved along with it.
+    pub(crate) fn relative_path(dir: &Path, path: &Path) -> Result<PathBuf, io::Error> {
+        let dir = fs::canonicalize(if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        })?;
+        let path = fs::canonicalize(path)?;
+        let common = dir
+            .components()
+            .zip(path.components())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let mut relative = PathBuf::new();
+        for _ in dir.components().skip(common) {
+            relative.push("..");
+        }
+        relative.extend(path.components().skip(common));
+        Ok(relative)
+    }
+
+    /// The file name of `path`, by which generated code refers to it.
+    pub(crate) fn file_name(path: &Path) -> String {
+        path.file_name()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// The directory `name` in litweb's cache directory of the current user,
+    /// created as needed. The cache directory is only accessible to its
+    /// owner, so that other users can neither read what is built there nor
+    /// plant files for litweb to pick up.
+    pub(crate) fn cache_dir(name: &str) -> Result<PathBuf, io::Error> {
+        let base = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no cache directory for the current user",
+                )
+            })?;
+        let litweb_dir = base.join("litweb");
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&litweb_dir)?;
+        // The directory may predate litweb creating it this way.
+        #[cfg(unix)]
+        fs::set_permissions(
+            &litweb_dir,
+            std::os::unix::fs::PermissionsExt::from_mode(0o700),
+        )?;
+        let dir = litweb_dir.join(name);
+        builder.create(&dir)?;
+        Ok(dir)
+    }
+
     /*
     macro_rules! impl_from_ty_for_ty {
         ($from_ty:path, $self_ty:path, $ctor:expr) => {
@@ -93,16 +179,55 @@ enum TangleOrWeave {
 }
 
 mod tangle_and_untangle;
+use tangle_and_untangle::{BlockMarker, GeneratedLanguage, GeneratedSignature, GeneratedTarget};
 
 mod tangle;
 mod untangle;
 
+mod source_map;
+pub use source_map::{SourceLocation, SourceMap};
+
+mod cargo;
+pub use cargo::{run_cargo, CargoError, CargoRun, MessageFormat};
+
+mod diagnostic;
+pub use diagnostic::Diagnostic;
+
+mod eval;
+pub use eval::EvalError;
+use eval::Evaluator;
+
+mod run;
+pub use run::{run_script, RunError, ScriptRun};
+
+#[cfg(feature = "validate")]
+mod validate;
+
+mod weave_and_unweave;
+
+mod unweave;
+mod weave;
+
 #[derive(Error, Debug)]
 pub enum TangleUntangleError {
     #[error("File extension is unrecognized")]
     UnrecognizedFileExt,
     #[error("Input file is not newer")]
     InputFileIsNotNewer,
+    #[error("Output file {} exists and was not generated by litweb", path.display())]
+    OutputFileIsNotGenerated { path: PathBuf },
+    #[error("Generated file {} was modified since it was last tangled", path.display())]
+    GeneratedFileModified { path: PathBuf },
+    #[error("Both the source and the generated file were modified since the last tangle")]
+    BothSidesModified,
+    #[error("Generated file {} is out of sync with its source, tangle it again", path.display())]
+    OutOfSync { path: PathBuf },
+    #[error("Line {line} of {} does not come from the source", path.display())]
+    LineNotFromSource { path: PathBuf, line: usize },
+    #[error("Line {line} of {} is not tangled into any generated file", path.display())]
+    LineNotTangled { path: PathBuf, line: usize },
+    #[error("Several outputs of the document would be written to {}", path.display())]
+    OutputPathCollision { path: PathBuf },
     #[error(transparent)]
     Io(#[from] io::Error),
     #[error(transparent)]
@@ -111,41 +236,476 @@ pub enum TangleUntangleError {
     TangleUntangleConversionError(#[from] tangle_and_untangle::ConversionError),
     #[error(transparent)]
     TangleUntangleGenerationError(#[from] tangle_and_untangle::GenerationError),
+    #[error(transparent)]
+    Located(Box<Diagnostic>),
+}
+
+/// Options controlling how a literate document is tangled.
+#[derive(Clone, Debug, Default)]
+pub struct TangleOptions {
+    /// Surround every code block in the generated file with marker comments
+    /// naming the source block it came from, so that edits to the generated
+    /// code can be merged back into exactly that block when untangling.
+    pub block_markers: bool,
+    /// Generate only the expansion of this named chunk, instead of every
+    /// unnamed code block in document order interleaved with the prose.
+    pub root_chunk: Option<String>,
+    /// Where to write the code blocks of languages other than the one of
+    /// the main output, keyed by code block language tag. Languages not
+    /// listed are written next to the main output with their own extension.
+    pub language_outputs: Vec<(String, PathBuf)>,
+    /// Wrap the main output in a `mod` block for every heading with a `mod`
+    /// attribute, nested by heading level, e.g. `{mod=parser}` for
+    /// `mod parser { ... }` or `{.pub mod=parser}` for `pub mod parser`.
+    pub modules: bool,
+    /// Write the prose of Rust output as rustdoc Markdown in doc comments
+    /// instead of plain comments: `//!` for the prose ahead of the first code
+    /// of the file or of a module, and `///` for prose followed by an item,
+    /// so that it ends up in the crate's documentation.
+    pub rustdoc: bool,
 }
 
+/// Tangles a `.lit.djot` document, or untangles a generated `.rs` file back
+/// into its document, and returns the paths of all files written.
 pub fn tangle_or_untangle(
     file_path: &Path,
     output_path: Option<&Path>,
     force: bool,
-) -> Result<(), TangleUntangleError> {
-    let Some((file_type, default_output_path, output_file_type)) = utils::determine_filetype_and_dest(file_path, TangleOrWeave::Tangle) else {
+    options: &TangleOptions,
+) -> Result<Vec<PathBuf>, TangleUntangleError> {
+    let Some((file_type, default_output_path, output_file_type)) =
+        utils::determine_filetype_and_dest(file_path, TangleOrWeave::Tangle)
+    else {
         return Err(TangleUntangleError::UnrecognizedFileExt);
     };
+    let is_tangle = matches!(file_type, FileType::SourceDjot);
+    let mut source_text = None;
     let output_path = match output_path {
+        // A document is tangled to the language of its code blocks unless
+        // the output path says otherwise.
+        None if is_tangle => {
+            let source = fs::read_to_string(file_path)?;
+            let lang =
+                tangle::detect_generated_language(&source).unwrap_or(GeneratedLanguage::Rust);
+            source_text = Some(source);
+            default_output_path.with_extension(lang.info().extension)
+        }
         None => default_output_path,
         Some(path) => path.to_owned(),
     };
-    let is_tangle = matches!(file_type, FileType::SourceDjot);
-    let Some(output_time_to_use) = utils::ensure_input_is_newer(file_path, &output_path, force)? else {
+    let Some(output_time_to_use) = utils::ensure_input_is_newer(file_path, &output_path, force)?
+    else {
         return Err(TangleUntangleError::InputFileIsNotNewer);
     };
-    let input_file = File::open(file_path)?;
+    match (file_type, output_file_type) {
+        (FileType::GeneratedModule(lang), FileType::SourceDjot) => {
+            untangle_file(file_path, &output_path, lang, force, output_time_to_use)
+                .map_err(|err| diagnostic::locate(err, &output_path, file_path))
+        }
+        (FileType::SourceDjot, FileType::GeneratedModule(_)) => tangle_file(
+            file_path,
+            source_text,
+            &output_path,
+            force,
+            options,
+            output_time_to_use,
+        )
+        .map_err(|err| diagnostic::locate(err, file_path, &output_path)),
+        _ => Err(TangleUntangleError::UnrecognizedFileExt),
+    }
+}
+
+/// Merges a generated file back into its source document, or recreates the
+/// document if there is none.
+fn untangle_file(
+    file_path: &Path,
+    output_path: &Path,
+    lang: GeneratedLanguage,
+    force: bool,
+    output_time_to_use: SystemTime,
+) -> Result<Vec<PathBuf>, TangleUntangleError> {
     let output_file;
-    if !is_tangle {
-        assert!(matches!(output_file_type, FileType::SourceDjot));
-        let generated_lines = untangle::analyze_line_groups(&input_file)?;
-        let source_lines = untangle::convert_line_groups(
-            generated_lines,
-            tangle_and_untangle::GeneratedLanguage::Rust,
+    let generated_text = fs::read_to_string(file_path)?;
+    let generated_lines = untangle::analyze_line_groups(generated_text.as_bytes(), lang)?;
+    if output_path.exists() {
+        let source = fs::read_to_string(output_path)?;
+        let source_hash = GeneratedSignature::split_generated_text(&generated_text, lang)
+            .and_then(|(_, signature)| signature.source_hash);
+        if !force && source_hash.is_some_and(|hash| hash != utils::content_hash(&source)) {
+            return Err(TangleUntangleError::BothSidesModified);
+        }
+        let source_blocks = tangle::analyze_source_blocks(source.as_bytes())?;
+        let source_edits = if untangle::has_block_markers(&generated_lines) {
+            untangle::convert_marked_line_groups(generated_lines, &source, source_blocks)?
+        } else {
+            untangle::convert_line_groups_to_edits(generated_lines, lang, &source, source_blocks)?
+        };
+        output_file = File::create(output_path)?;
+        untangle::generate_edited_output(&output_file, &source, source_edits)?;
+    } else {
+        let source_lines = untangle::convert_line_groups(generated_lines, lang)?;
+        output_file = File::create(output_path)?;
+        untangle::generate_output(&output_file, source_lines)?;
+    }
+    drop(output_file);
+    // Both sides are in sync again, so record that in the signature of
+    // the generated file, leaving its modification time as it was.
+    if let Some((body, signature)) = GeneratedSignature::split_generated_text(&generated_text, lang)
+    {
+        let signature = GeneratedSignature {
+            source_hash: Some(utils::content_hash(fs::read_to_string(output_path)?)),
+            generated_hash: Some(utils::content_hash(body)),
+            ..signature
+        };
+        fs::write(file_path, format!("{body}{}\n", signature.line()))?;
+        filetime::set_file_mtime(
+            file_path,
+            filetime::FileTime::from_system_time(output_time_to_use),
+        )?;
+    }
+    filetime::set_file_mtime(
+        output_path,
+        filetime::FileTime::from_system_time(output_time_to_use),
+    )?;
+    Ok(vec![output_path.to_owned()])
+}
+
+/// Tangles a document into its main output at `output_path` and every
+/// other file its code blocks are directed to.
+fn tangle_file(
+    file_path: &Path,
+    source_text: Option<String>,
+    output_path: &Path,
+    force: bool,
+    options: &TangleOptions,
+    output_time_to_use: SystemTime,
+) -> Result<Vec<PathBuf>, TangleUntangleError> {
+    let source = match source_text {
+        Some(source) => source,
+        None => fs::read_to_string(file_path)?,
+    };
+    let lang = output_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(GeneratedLanguage::from_extension)
+        .or_else(|| tangle::detect_generated_language(&source))
+        .unwrap_or(GeneratedLanguage::Rust);
+    let source_blocks = tangle::analyze_source_blocks(source.as_bytes())?;
+    let source_name = utils::file_name(file_path);
+    let targets = tangle::convert_source_blocks(source_blocks, lang, &source_name, options)?;
+    #[cfg(feature = "validate")]
+    for target in &targets {
+        validate::validate_syntax(target)?;
+    }
+    let targets = targets
+        .into_iter()
+        .map(|target| {
+            let target_path = target_path(&target, lang, file_path, output_path, options);
+            (target_path, target)
+        })
+        .collect::<Vec<_>>();
+    // Targets must not overwrite each other, e.g. a `file` attribute naming
+    // the default output.
+    let mut target_paths = HashSet::new();
+    for (target_path, _) in &targets {
+        let normalized: PathBuf = target_path
+            .components()
+            .filter(|component| !matches!(component, Component::CurDir))
+            .collect();
+        if !target_paths.insert(normalized) {
+            return Err(TangleUntangleError::OutputPathCollision {
+                path: target_path.clone(),
+            });
+        }
+    }
+    // Check every target before writing any, so that a conflict leaves
+    // all of them untouched.
+    if !force {
+        for (target_path, target) in &targets {
+            ensure_output_is_generated(target_path, target.lang)?;
+        }
+    }
+    let mut produced = vec![];
+    for (target_path, target) in targets {
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let output_file = File::create(&target_path)?;
+        let mappings = tangle::generate_output(&output_file, target)?;
+        drop(output_file);
+        let map_dir = target_path.parent().unwrap_or(Path::new(""));
+        SourceMap::new(utils::relative_path(map_dir, file_path)?, mappings).store(&target_path)?;
+        filetime::set_file_mtime(
+            &target_path,
+            filetime::FileTime::from_system_time(output_time_to_use),
         )?;
+        produced.push(target_path);
+    }
+    Ok(produced)
+}
+
+/// Where a generated target of the document at `source_path` is written,
+/// given the path of its main output in `lang`.
+fn target_path(
+    target: &GeneratedTarget,
+    lang: GeneratedLanguage,
+    source_path: &Path,
+    output_path: &Path,
+    options: &TangleOptions,
+) -> PathBuf {
+    match &target.file {
+        None if target.lang == lang => output_path.to_owned(),
+        None => options
+            .language_outputs
+            .iter()
+            .find(|(tag, _)| GeneratedLanguage::from_code_block_tag(tag) == Some(target.lang))
+            .map(|(_, path)| path.clone())
+            .unwrap_or_else(|| output_path.with_extension(target.lang.info().extension)),
+        Some(file) => source_path.parent().unwrap_or(Path::new("")).join(file),
+    }
+}
+
+/// Refuses to let tangling overwrite a file that litweb did not generate,
+/// or that was edited since it was generated.
+fn ensure_output_is_generated(
+    path: &Path,
+    lang: GeneratedLanguage,
+) -> Result<(), TangleUntangleError> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let existing = fs::read_to_string(path)?;
+    let Some((body, signature)) = GeneratedSignature::split_generated_text(&existing, lang) else {
+        return Err(TangleUntangleError::OutputFileIsNotGenerated {
+            path: path.to_owned(),
+        });
+    };
+    if signature
+        .generated_hash
+        .is_some_and(|hash| hash != utils::content_hash(body))
+    {
+        return Err(TangleUntangleError::GeneratedFileModified {
+            path: path.to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Finds where the lines of a generated file come from, refusing if either
+/// it or its source changed since it was tangled.
+///
+/// The source map written next to the file is used when there is one.
+/// Otherwise the file is assumed to be tangled from the `.lit.djot` beside
+/// it, which is tangled again in memory to recover the mapping.
+pub fn load_source_map(generated_path: &Path) -> Result<SourceMap, TangleUntangleError> {
+    let Some(lang) = generated_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(GeneratedLanguage::from_extension)
+    else {
+        return Err(TangleUntangleError::UnrecognizedFileExt);
+    };
+    let generated_text = fs::read_to_string(generated_path)?;
+    let Some((body, signature)) = GeneratedSignature::split_generated_text(&generated_text, lang)
+    else {
+        return Err(TangleUntangleError::OutputFileIsNotGenerated {
+            path: generated_path.to_owned(),
+        });
+    };
+    let generated_dir = generated_path.parent().unwrap_or(Path::new(""));
+    let stored_map = SourceMap::load(generated_path)
+        .ok()
+        .map(|source_map| source_map.located_in(generated_dir));
+    let source_path = match &stored_map {
+        Some(source_map) => source_map.source(),
+        None => generated_path.with_extension("lit.djot"),
+    };
+    let source = fs::read_to_string(&source_path)?;
+    let out_of_sync = signature
+        .source_hash
+        .is_some_and(|hash| hash != utils::content_hash(&source))
+        || signature
+            .generated_hash
+            .is_some_and(|hash| hash != utils::content_hash(body));
+    if out_of_sync {
+        return Err(TangleUntangleError::OutOfSync {
+            path: generated_path.to_owned(),
+        });
+    }
+    if let Some(source_map) = stored_map {
+        return Ok(source_map);
+    }
+    let options = TangleOptions {
+        block_markers: generated_text
+            .lines()
+            .any(|line| BlockMarker::parse_start_line(line, lang).is_some()),
+        ..TangleOptions::default()
+    };
+    let source_blocks = tangle::analyze_source_blocks(source.as_bytes())?;
+    let source_name = utils::file_name(&source_path);
+    for target in tangle::convert_source_blocks(source_blocks, lang, &source_name, &options)? {
+        if target.lang != lang {
+            continue;
+        }
+        let mut output = vec![];
+        let mappings = tangle::generate_output(&mut output, target)?;
+        if output == generated_text.as_bytes() {
+            let source = utils::relative_path(generated_dir, &source_path)?;
+            return Ok(SourceMap::new(source, mappings).located_in(generated_dir));
+        }
+    }
+    Err(TangleUntangleError::OutOfSync {
+        path: generated_path.to_owned(),
+    })
+}
+
+/// Finds the line of the `.lit.djot` document that a line of a generated
+/// file was tangled from.
+pub fn locate_in_source(
+    generated_path: &Path,
+    line: usize,
+) -> Result<SourceLocation, TangleUntangleError> {
+    load_source_map(generated_path)?
+        .lookup(line)
+        .ok_or_else(|| TangleUntangleError::LineNotFromSource {
+            path: generated_path.to_owned(),
+            line,
+        })
+}
+
+/// Finds every line of the generated files that a line of a `.lit.djot`
+/// document is tangled into. A line of a named chunk shows up once for every
+/// reference to the chunk.
+///
+/// The generated files are expected where tangling with default options puts
+/// them, and must be in sync with the document.
+pub fn locate_in_generated(
+    source_path: &Path,
+    line: usize,
+) -> Result<Vec<SourceLocation>, TangleUntangleError> {
+    let Some((FileType::SourceDjot, default_output_path, _)) =
+        utils::determine_filetype_and_dest(source_path, TangleOrWeave::Tangle)
+    else {
+        return Err(TangleUntangleError::UnrecognizedFileExt);
+    };
+    let source = fs::read_to_string(source_path)?;
+    let lang = tangle::detect_generated_language(&source).unwrap_or(GeneratedLanguage::Rust);
+    let output_path = default_output_path.with_extension(lang.info().extension);
+    let options = TangleOptions::default();
+    let source_blocks = tangle::analyze_source_blocks(source.as_bytes())?;
+    let mut locations = vec![];
+    let source_name = utils::file_name(source_path);
+    for target in tangle::convert_source_blocks(source_blocks, lang, &source_name, &options)? {
+        let generated_path = target_path(&target, lang, source_path, &output_path, &options);
+        let source_map = load_source_map(&generated_path)?;
+        locations.extend(
+            source_map
+                .reverse_lookup(line)
+                .into_iter()
+                .map(|(line, column)| SourceLocation {
+                    file: generated_path.clone(),
+                    line,
+                    column,
+                }),
+        );
+    }
+    if locations.is_empty() {
+        return Err(TangleUntangleError::LineNotTangled {
+            path: source_path.to_owned(),
+            line,
+        });
+    }
+    Ok(locations)
+}
+
+#[derive(Error, Debug)]
+pub enum WeaveUnweaveError {
+    #[error("File extension is unrecognized")]
+    UnrecognizedFileExt,
+    #[error("Input file is not newer")]
+    InputFileIsNotNewer,
+    #[error("Document {} exists and unweaving would replace it, use --force to do so", path.display())]
+    SourceExists { path: PathBuf },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    WeaveUnweaveAnalysisError(#[from] weave_and_unweave::AnalysisError),
+    #[error(transparent)]
+    WeaveUnweaveConversionError(#[from] weave_and_unweave::ConversionError),
+    #[error(transparent)]
+    WeaveUnweaveGenerationError(#[from] weave_and_unweave::GenerationError),
+}
+
+/// Options controlling how a literate document is woven.
+#[derive(Clone, Debug, Default)]
+pub struct WeaveOptions {
+    /// Compile and run every Rust code block with an `eval` class or
+    /// attribute, and add what it printed below it.
+    pub eval: bool,
+    /// The scratch crate evaluated blocks are built in, along with the cache
+    /// of their results. Defaults to a directory of the document's own in
+    /// the user's cache directory, e.g. `~/.cache/litweb/eval/<hash>`.
+    pub scratch_dir: Option<PathBuf>,
+}
+
+pub fn weave_or_unweave(
+    file_path: &Path,
+    output_path: Option<&Path>,
+    force: bool,
+    options: &WeaveOptions,
+) -> Result<(), WeaveUnweaveError> {
+    let Some((file_type, default_output_path, output_file_type)) =
+        utils::determine_filetype_and_dest(file_path, TangleOrWeave::Weave)
+    else {
+        return Err(WeaveUnweaveError::UnrecognizedFileExt);
+    };
+    let output_path = match output_path {
+        None => default_output_path,
+        Some(path) => path.to_owned(),
+    };
+    let is_weave = matches!(file_type, FileType::SourceDjot);
+    let Some(output_time_to_use) = utils::ensure_input_is_newer(file_path, &output_path, force)?
+    else {
+        return Err(WeaveUnweaveError::InputFileIsNotNewer);
+    };
+    let input_file = File::open(file_path)?;
+    let output_file;
+    if !is_weave {
+        if !matches!(output_file_type, FileType::SourceDjot) {
+            return Err(WeaveUnweaveError::UnrecognizedFileExt);
+        }
+        // Unweaving does not give back the document as it was written, such
+        // as its choice of markup or the punctuation that Djot made smart,
+        // so the document is only replaced when asked to.
+        if output_path.exists() && !force {
+            return Err(WeaveUnweaveError::SourceExists { path: output_path });
+        }
+        let markdown = unweave::analyze_markdown_text(&input_file)?;
+        let source_lines = unweave::convert_markdown_text(&markdown)?;
         output_file = File::create(&output_path)?;
-        untangle::generate_output(&output_file, source_lines)?;
+        unweave::generate_output(&output_file, source_lines)?;
     } else {
-        assert!(matches!(output_file_type, FileType::GeneratedRustModule));
-        let source_blocks = tangle::analyze_source_blocks(&input_file)?;
-        let generated_lines = tangle::convert_source_blocks(source_blocks)?;
+        if !matches!(output_file_type, FileType::GeneratedMarkdown) {
+            return Err(WeaveUnweaveError::UnrecognizedFileExt);
+        }
+        let source = weave::analyze_source_text(&input_file)?;
+        let mut evaluator = None;
+        if options.eval {
+            // Documents get a scratch crate each, so that weaving several
+            // at once does not mix up their builds.
+            let scratch_dir = match &options.scratch_dir {
+                Some(scratch_dir) => scratch_dir.clone(),
+                None => {
+                    let source_path = fs::canonicalize(file_path)?;
+                    let hash = utils::content_hash(source_path.as_os_str().as_encoded_bytes());
+                    utils::cache_dir("eval")?.join(format!("{hash:016x}"))
+                }
+            };
+            evaluator = Some(Evaluator::new(scratch_dir, utils::file_name(file_path)));
+        }
+        let generated_lines = weave::convert_source_text(&source, evaluator.as_mut())?;
         output_file = File::create(&output_path)?;
-        tangle::generate_output(&output_file, generated_lines)?;
+        weave::generate_output(&output_file, generated_lines)?;
     }
     drop(output_file);
     filetime::set_file_mtime(
@@ -155,5 +715,105 @@ pub fn tangle_or_untangle(
     Ok(())
 }
 
-mod weave {}
-mod unweave {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn tangling_markdown_is_rejected() {
+        let result = tangle_or_untangle(
+            Path::new("notes.md"),
+            None,
+            false,
+            &TangleOptions::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(TangleUntangleError::UnrecognizedFileExt)
+        ));
+    }
+
+    #[test]
+    fn file_attribute_naming_default_output_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_path = dir.path().join("doc.lit.djot");
+        fs::write(
+            &source_path,
+            "```rust\nfn main() {}\n```\n\n{file=./doc.rs}\n```rust\nfn helper() {}\n```\n",
+        )
+        .unwrap();
+        let result = tangle_or_untangle(&source_path, None, false, &TangleOptions::default());
+        assert!(matches!(
+            result,
+            Err(TangleUntangleError::OutputPathCollision { .. })
+        ));
+        assert!(!dir.path().join("doc.rs").exists());
+    }
+
+    #[test]
+    fn tangled_lines_locate_their_source_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_path = dir.path().join("doc.lit.djot");
+        fs::write(
+            &source_path,
+            "# Doc\n\n```rust\nfn main() {\n    helper();\n}\n```\n\nA helper.\n\n```rust\nfn helper() {}\n```\n",
+        )
+        .unwrap();
+        let outputs =
+            tangle_or_untangle(&source_path, None, false, &TangleOptions::default()).unwrap();
+        let generated_path = dir.path().join("doc.rs");
+        assert_eq!(outputs, vec![generated_path.clone()]);
+        assert!(SourceMap::path_for(&generated_path).exists());
+
+        let generated = fs::read_to_string(&generated_path).unwrap();
+        let line_of = |text: &str| generated.lines().position(|line| line == text).unwrap() + 1;
+        let location = locate_in_source(&generated_path, line_of("    helper();")).unwrap();
+        assert_eq!((location.line, location.column), (5, 1));
+        let location = locate_in_source(&generated_path, line_of("fn helper() {}")).unwrap();
+        assert_eq!(location.line, 12);
+        assert_eq!(
+            locate_in_generated(&source_path, 12)
+                .unwrap()
+                .iter()
+                .map(|location| location.line)
+                .collect::<Vec<_>>(),
+            vec![line_of("fn helper() {}")]
+        );
+    }
+
+    #[test]
+    fn unweaving_keeps_existing_document_unless_forced() {
+        let dir = tempfile::tempdir().unwrap();
+        let markdown_path = dir.path().join("doc.md");
+        let source_path = dir.path().join("doc.lit.djot");
+        fs::write(&source_path, "{chunk=greeting}\n```rust\nhello();\n```\n").unwrap();
+        fs::write(&markdown_path, "# Doc\n").unwrap();
+        let source_time = fs::metadata(&source_path).unwrap().modified().unwrap();
+        filetime::set_file_mtime(
+            &markdown_path,
+            filetime::FileTime::from_system_time(source_time + Duration::from_secs(1)),
+        )
+        .unwrap();
+        let result = weave_or_unweave(&markdown_path, None, false, &WeaveOptions::default());
+        assert!(matches!(
+            result,
+            Err(WeaveUnweaveError::SourceExists { path }) if path == source_path
+        ));
+        assert!(fs::read_to_string(&source_path)
+            .unwrap()
+            .starts_with("{chunk=greeting}"));
+
+        weave_or_unweave(&markdown_path, None, true, &WeaveOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(&source_path).unwrap(), "# Doc\n");
+    }
+
+    #[test]
+    fn weaving_rust_is_rejected() {
+        let result = weave_or_unweave(Path::new("main.rs"), None, false, &WeaveOptions::default());
+        assert!(matches!(
+            result,
+            Err(WeaveUnweaveError::UnrecognizedFileExt)
+        ));
+    }
+}
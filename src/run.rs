@@ -0,0 +1,246 @@
+#[derive(Error, Debug)]
+pub enum RunError {
+    #[error("Document has no Rust code to run")]
+    NoRustCode,
+    #[error("Building the document failed ({})", .0.status)]
+    BuildFailed(CargoRun),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Tangle(#[from] TangleUntangleError),
+    #[error(transparent)]
+    Cargo(#[from] CargoError),
+}
+
+/// How a script run went.
+#[derive(Debug)]
+pub struct ScriptRun {
+    /// The exit status of the script.
+    pub status: ExitStatus,
+    /// Warnings from building the script, as in `CargoRun`.
+    pub warnings: Vec<TangleUntangleError>,
+}
+
+/// Runs a `.lit.djot` document as a script with the given arguments.
+///
+/// The document's Rust code is tangled into the `main.rs` of a cargo project
+/// in the user's cache directory, whose dependencies are declared by the
+/// document's `toml` blocks, either as whole manifest sections or as bare
+/// entries of `[dependencies]`. Each document has a project of its own,
+/// named after the hash of its canonical path, so that running it again
+/// after an edit only rebuilds what changed. Cargo is run every time, to
+/// pick up anything that changed in the project since. Builds are offline,
+/// and their diagnostics point into the document.
+pub fn run_script(source_path: &Path, args: &[OsString]) -> Result<ScriptRun, RunError> {
+    let canonical_path = fs::canonicalize(source_path)?;
+    let hash = utils::content_hash(canonical_path.as_os_str().as_encoded_bytes());
+    let project_dir = utils::cache_dir("run")?.join(format!("{hash:016x}"));
+    run_script_in(source_path, args, &project_dir)
+}
+
+/// Like `run_script`, with the script's project at `project_dir`.
+fn run_script_in(
+    source_path: &Path,
+    args: &[OsString],
+    project_dir: &Path,
+) -> Result<ScriptRun, RunError> {
+    let source = fs::read_to_string(source_path)?;
+    let source_blocks = tangle::analyze_source_blocks(source.as_bytes())
+        .map_err(|err| locate(err.into(), source_path))?;
+    let manifest = script_manifest(&source_blocks);
+    let targets = tangle::convert_source_blocks(
+        source_blocks,
+        GeneratedLanguage::Rust,
+        &utils::file_name(source_path),
+        &TangleOptions::default(),
+    )
+    .map_err(|err| locate(err.into(), source_path))?;
+    #[cfg(feature = "validate")]
+    for target in &targets {
+        crate::validate::validate_syntax(target).map_err(|err| locate(err.into(), source_path))?;
+    }
+    let Some(target) = targets
+        .into_iter()
+        .find(|target| target.file.is_none() && target.lang == GeneratedLanguage::Rust)
+    else {
+        return Err(RunError::NoRustCode);
+    };
+    let mut main = vec![];
+    let mappings = tangle::generate_output(&mut main, target)
+        .map_err(|err| locate(err.into(), source_path))?;
+    let main_path = project_dir.join("src").join("main.rs");
+    fs::create_dir_all(project_dir.join("src"))?;
+    write_if_changed(&project_dir.join("Cargo.toml"), manifest.as_bytes())?;
+    write_if_changed(&main_path, &main)?;
+    let source_dir = main_path.parent().unwrap_or(Path::new(""));
+    SourceMap::new(utils::relative_path(source_dir, source_path)?, mappings).store(&main_path)?;
+    let cargo_args: Vec<OsString> = vec![
+        "build".into(),
+        "--quiet".into(),
+        "--offline".into(),
+        "--manifest-path".into(),
+        project_dir.join("Cargo.toml").into(),
+    ];
+    let build = cargo::run_cargo_in(&cargo_args, MessageFormat::Human, project_dir.to_owned())?;
+    if !build.status.success() {
+        return Err(RunError::BuildFailed(build));
+    }
+    let binary = project_dir
+        .join("target")
+        .join("debug")
+        .join(format!("{SCRIPT_NAME}{}", env::consts::EXE_SUFFIX));
+    Ok(ScriptRun {
+        status: Command::new(binary).args(args).status()?,
+        warnings: build.warnings,
+    })
+}
+
+/// Writes `contents` to `path` unless it already holds them, so that cargo
+/// does not take an unchanged file for a reason to rebuild.
+fn write_if_changed(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    if fs::read(path).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+    fs::write(path, contents)
+}
+
+const SCRIPT_NAME: &str = "litweb-script";
+
+/// Writes the manifest of a script's project, with the contents of the
+/// document's `toml` blocks added to it.
+///
+/// Blocks that start with a table header are added as they are. The bare
+/// entries of the other blocks are gathered under the first
+/// `[dependencies]` header of those, or under one of their own at the end,
+/// so that the manifest never declares the table twice.
+fn script_manifest(source_blocks: &[SourceToplevelBlock]) -> String {
+    let mut manifest = format!(
+        "[package]\nname = \"{SCRIPT_NAME}\"\nversion = \"0.0.0\"\nedition = \"2021\"\npublish = false\n\n[workspace]\n"
+    );
+    let mut tables = vec![];
+    let mut bare_entries = vec![];
+    for block in source_blocks {
+        let SourceToplevelBlock::VerbatimBlock {
+            lang: GeneratedLanguage::Toml,
+            lines,
+            chunk: None,
+            file: None,
+            ..
+        } = block
+        else {
+            continue;
+        };
+        let starts_with_table = lines
+            .iter()
+            .map(|line| line.trim())
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .is_some_and(|line| line.starts_with('['));
+        if starts_with_table {
+            tables.push(lines);
+        } else {
+            bare_entries.extend(lines);
+        }
+    }
+    let mut bare_entries_added = bare_entries.is_empty();
+    for lines in tables {
+        manifest.push('\n');
+        for line in lines {
+            manifest.push_str(line);
+            manifest.push('\n');
+            if !bare_entries_added && line.trim() == "[dependencies]" {
+                push_lines(&mut manifest, &bare_entries);
+                bare_entries_added = true;
+            }
+        }
+    }
+    if !bare_entries_added {
+        manifest.push_str("\n[dependencies]\n");
+        push_lines(&mut manifest, &bare_entries);
+    }
+    manifest
+}
+
+fn push_lines(text: &mut String, lines: &[&String]) {
+    for line in lines {
+        text.push_str(line);
+        text.push('\n');
+    }
+}
+
+fn locate(error: TangleUntangleError, source_path: &Path) -> TangleUntangleError {
+    diagnostic::locate(error, source_path, source_path)
+}
+
+use crate::cargo;
+use crate::cargo::CargoError;
+use crate::cargo::CargoRun;
+use crate::cargo::MessageFormat;
+use crate::diagnostic;
+use crate::source_map::SourceMap;
+use crate::tangle;
+use crate::tangle_and_untangle::GeneratedLanguage;
+use crate::tangle_and_untangle::SourceToplevelBlock;
+use crate::utils;
+use crate::TangleOptions;
+use crate::TangleUntangleError;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::process::ExitStatus;
+use thiserror::Error;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(source: &str) -> String {
+        script_manifest(&tangle::analyze_source_blocks(source.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn bare_dependency_blocks_share_one_table() {
+        let manifest = manifest(
+            "```toml\nregex = \"1\"\n```\n\nText.\n\n```toml\nserde = \"1\"\n```\n\n```rust\nfn main() {}\n```\n",
+        );
+        assert_eq!(manifest.matches("[dependencies]").count(), 1);
+        assert!(manifest.ends_with("[dependencies]\nregex = \"1\"\nserde = \"1\"\n"));
+    }
+
+    #[test]
+    fn bare_dependencies_join_explicit_table() {
+        let manifest = manifest(
+            "```toml\nregex = \"1\"\n```\n\n```toml\n[dependencies]\nserde = \"1\"\n\n[profile.dev]\nopt-level = 1\n```\n",
+        );
+        assert_eq!(manifest.matches("[dependencies]").count(), 1);
+        assert!(manifest.contains("[dependencies]\nregex = \"1\"\nserde = \"1\"\n"));
+    }
+
+    #[test]
+    fn document_without_rust_code_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_path = dir.path().join("script.lit.djot");
+        fs::write(&source_path, "# Script\n\n```toml\nregex = \"1\"\n```\n").unwrap();
+        let result = run_script_in(&source_path, &[], &dir.path().join("project"));
+        assert!(matches!(result, Err(RunError::NoRustCode)));
+    }
+
+    #[test]
+    fn script_runs_with_its_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_path = dir.path().join("script.lit.djot");
+        fs::write(
+            &source_path,
+            "Exits with the number of its arguments.\n\n```rust\nfn main() {\n    std::process::exit(std::env::args().skip(1).count() as i32);\n}\n```\n",
+        )
+        .unwrap();
+        let project_dir = dir.path().join("project");
+        let args = ["a".into(), "b".into()];
+        let run = run_script_in(&source_path, &args, &project_dir).unwrap();
+        assert_eq!(run.status.code(), Some(2));
+        assert!(run.warnings.is_empty());
+        assert!(SourceMap::path_for(&project_dir.join("src").join("main.rs")).exists());
+    }
+}
@@ -0,0 +1,183 @@
+/// Maps the lines of a generated file back to the `.lit.djot` document they
+/// were tangled from.
+///
+/// Tangling writes one next to every generated file, as JSON in
+/// `<generated file>.litmap`. Lines that do not come from the document, such
+/// as block markers, the blank lines between groups and the signature, have
+/// no mapping.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SourceMap {
+    /// The source document, relative to the directory of the source map.
+    source: PathBuf,
+    mappings: Vec<SourceMapping>,
+    #[serde(skip)]
+    base_dir: PathBuf,
+}
+
+/// A run of generated lines that come from consecutive source lines.
+///
+/// Line `generated_lines.start + n` comes from source line `line + n`, and
+/// its column `generated_column` corresponds to source column `column`.
+/// Lines and columns are 1-based, and columns count characters.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SourceMapping {
+    pub(crate) generated_lines: Range<usize>,
+    pub(crate) generated_column: usize,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+/// A position within a source document. Line and column are 1-based.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourceMap {
+    pub(crate) fn new(source: PathBuf, mappings: Vec<SourceMapping>) -> Self {
+        SourceMap {
+            source,
+            mappings,
+            base_dir: PathBuf::new(),
+        }
+    }
+
+    /// Resolves the source path against `dir`, the directory of the
+    /// generated file the map belongs to.
+    pub(crate) fn located_in(mut self, dir: &Path) -> Self {
+        self.base_dir = dir.to_owned();
+        self
+    }
+
+    /// The source document the generated file was tangled from.
+    pub fn source(&self) -> PathBuf {
+        self.base_dir.join(&self.source)
+    }
+
+    /// The path of the source map belonging to a generated file.
+    pub fn path_for(generated_path: &Path) -> PathBuf {
+        let mut path = generated_path.as_os_str().to_owned();
+        path.push(".litmap");
+        PathBuf::from(path)
+    }
+
+    /// Reads the source map belonging to a generated file.
+    pub fn load(generated_path: &Path) -> io::Result<Self> {
+        let map_path = Self::path_for(generated_path);
+        let mut source_map: SourceMap = serde_json::from_reader(File::open(&map_path)?)?;
+        source_map.base_dir = map_path.parent().unwrap_or(Path::new("")).to_owned();
+        Ok(source_map)
+    }
+
+    pub(crate) fn store(&self, generated_path: &Path) -> io::Result<()> {
+        let file = File::create(Self::path_for(generated_path))?;
+        serde_json::to_writer_pretty(&file, self)?;
+        writeln!(&file)
+    }
+
+    /// Translates a 1-based line of the generated file into the location in
+    /// the source it was tangled from.
+    pub fn lookup(&self, generated_line: usize) -> Option<SourceLocation> {
+        let mapping = self.mapping_for(generated_line)?;
+        Some(self.location(mapping, generated_line, mapping.column))
+    }
+
+    /// Translates a 1-based line and column of the generated file. Columns
+    /// that fall into text added by tangling, such as comment markers or
+    /// chunk indentation, map to the start of the source line's text.
+    pub fn lookup_position(
+        &self,
+        generated_line: usize,
+        generated_column: usize,
+    ) -> Option<SourceLocation> {
+        let mapping = self.mapping_for(generated_line)?;
+        let column = mapping.column + generated_column.saturating_sub(mapping.generated_column);
+        Some(self.location(mapping, generated_line, column))
+    }
+
+    /// Finds every 1-based line and column of the generated file that comes
+    /// from the given line of the source, in the order they appear.
+    pub fn reverse_lookup(&self, source_line: usize) -> Vec<(usize, usize)> {
+        let mut generated = self
+            .mappings
+            .iter()
+            .filter(|mapping| {
+                (mapping.line..mapping.line + mapping.generated_lines.len()).contains(&source_line)
+            })
+            .map(|mapping| {
+                (
+                    mapping.generated_lines.start + (source_line - mapping.line),
+                    mapping.generated_column,
+                )
+            })
+            .collect::<Vec<_>>();
+        generated.sort_unstable();
+        generated
+    }
+
+    fn mapping_for(&self, generated_line: usize) -> Option<&SourceMapping> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.generated_lines.contains(&generated_line))
+    }
+
+    fn location(
+        &self,
+        mapping: &SourceMapping,
+        generated_line: usize,
+        column: usize,
+    ) -> SourceLocation {
+        SourceLocation {
+            file: self.source(),
+            line: mapping.line + (generated_line - mapping.generated_lines.start),
+            column,
+        }
+    }
+}
+
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_map_loads_relative_to_generated_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let generated_dir = dir.path().join("src");
+        std::fs::create_dir(&generated_dir).unwrap();
+        let generated_path = generated_dir.join("main.rs");
+        let mappings = vec![SourceMapping {
+            generated_lines: 3..5,
+            generated_column: 5,
+            line: 10,
+            column: 1,
+        }];
+        SourceMap::new(PathBuf::from("../doc.lit.djot"), mappings.clone())
+            .store(&generated_path)
+            .unwrap();
+
+        let source_map = SourceMap::load(&generated_path).unwrap();
+        assert_eq!(source_map.mappings, mappings);
+        assert_eq!(source_map.source(), generated_dir.join("../doc.lit.djot"));
+        assert_eq!(
+            source_map.lookup_position(4, 7),
+            Some(SourceLocation {
+                file: generated_dir.join("../doc.lit.djot"),
+                line: 11,
+                column: 3,
+            })
+        );
+        assert_eq!(source_map.lookup(5), None);
+        assert_eq!(source_map.reverse_lookup(11), vec![(4, 5)]);
+    }
+}
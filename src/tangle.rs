@@ -1,63 +1,1046 @@
+/// Writes the generated file, returning where each of its lines came from.
 pub(crate) fn generate_output(
     mut writer: impl io::Write,
-    generated: Vec<GeneratedLineGroup>,
-) -> Result<(), GenerationError> {
+    target: GeneratedTarget,
+) -> Result<Vec<SourceMapping>, GenerationError> {
+    let GeneratedTarget {
+        lang,
+        line_groups,
+        line_origins,
+        ..
+    } = target;
+    // The body is buffered so that its hash can go into the signature.
+    let mut body: Vec<u8> = vec![];
+    let mut mappings = SourceMappingBuilder::default();
+    let mut trailing_signature = None;
     let mut first_group = true;
-    for line_group in generated {
+    for (line_group, origins) in line_groups.into_iter().zip(line_origins) {
         match line_group {
             GeneratedLineGroup::Preamble => {}
             GeneratedLineGroup::CodeLineGroup(lines) => {
                 if !mem::replace(&mut first_group, false) {
-                    writeln!(writer)?;
+                    writeln!(body)?;
+                    mappings.skip_line();
                 }
-                for line in lines {
-                    writeln!(writer, "{line}")?;
+                for (line, origin) in lines.iter().zip(origins_for(&origins, lines.len())) {
+                    writeln!(body, "{line}")?;
+                    mappings.push_line(origin);
                 }
             }
-            GeneratedLineGroup::Postamble { source_lang } => {
-                assert!(matches!(source_lang, SourceLanguage::Djot));
+            GeneratedLineGroup::MarkedCodeLineGroup { marker, lines } => {
                 if !mem::replace(&mut first_group, false) {
-                    writeln!(writer)?;
+                    writeln!(body)?;
+                    mappings.skip_line();
                 }
-                writeln!(writer, "{signature}", signature = GENERATED_SIGNATURE)?;
+                writeln!(body, "{}", marker.start_line(lang))?;
+                mappings.skip_line();
+                for (line, origin) in lines.iter().zip(origins_for(&origins, lines.len())) {
+                    writeln!(body, "{line}")?;
+                    mappings.push_line(origin);
+                }
+                writeln!(body, "{}", marker.end_line(lang))?;
+                mappings.skip_line();
+            }
+            GeneratedLineGroup::Postamble {
+                source_lang,
+                signature,
+            } => {
+                if !matches!(source_lang, SourceLanguage::Djot) {
+                    return Err(GenerationError::UnsupportedSourceLanguage);
+                }
+                if !mem::replace(&mut first_group, false) {
+                    writeln!(body)?;
+                }
+                trailing_signature = Some(signature);
             }
         }
     }
-    Ok(())
+    writer.write_all(&body)?;
+    if let Some(mut signature) = trailing_signature {
+        signature.generated_hash = Some(utils::content_hash(&body));
+        writeln!(writer, "{signature}", signature = signature.line())?;
+    }
+    Ok(mappings.finish())
+}
+
+fn origins_for(
+    origins: &[Option<LineOrigin>],
+    line_count: usize,
+) -> impl Iterator<Item = Option<LineOrigin>> + '_ {
+    (0..line_count).map(|index| origins.get(index).copied().flatten())
+}
+
+/// Collects the origins of generated lines into runs of consecutive lines.
+#[derive(Default)]
+struct SourceMappingBuilder {
+    mappings: Vec<SourceMapping>,
+    next_line: usize,
+}
+
+impl SourceMappingBuilder {
+    fn skip_line(&mut self) {
+        self.push_line(None);
+    }
+
+    fn push_line(&mut self, origin: Option<LineOrigin>) {
+        let generated_line = self.next_line + 1;
+        self.next_line += 1;
+        let Some(origin) = origin else {
+            return;
+        };
+        if let Some(last) = self.mappings.last_mut() {
+            let continues_last = last.generated_lines.end == generated_line
+                && last.line + last.generated_lines.len() == origin.line
+                && last.column == origin.column
+                && last.generated_column == origin.generated_column;
+            if continues_last {
+                last.generated_lines.end += 1;
+                return;
+            }
+        }
+        self.mappings.push(SourceMapping {
+            generated_lines: generated_line..generated_line + 1,
+            generated_column: origin.generated_column,
+            line: origin.line,
+            column: origin.column,
+        });
+    }
+
+    fn finish(self) -> Vec<SourceMapping> {
+        self.mappings
+    }
 }
 
+/// Converts the source blocks into the line groups of every file they
+/// generate. `source_name` is the file name of the source, which failing
+/// examples refer to.
+///
+/// Code blocks with a `file` attribute go to that file, as does the
+/// expansion of a named chunk carrying one. Code blocks in languages other
+/// than `lang` go to one output per language. Everything else goes to the
+/// default output, which is left out when all code has been directed
+/// elsewhere.
 pub(crate) fn convert_source_blocks(
     blocks: Vec<SourceToplevelBlock>,
-) -> Result<Vec<GeneratedLineGroup>, ConversionError> {
-    let mut generated = vec![];
-    for block in blocks {
+    lang: GeneratedLanguage,
+    source_name: &str,
+    options: &TangleOptions,
+) -> Result<Vec<GeneratedTarget>, ConversionError> {
+    let mut chunks = ChunkTable::collect(&blocks)?;
+    let Some(SourceToplevelBlock::Postamble { source_hash }) = blocks.last() else {
+        return Err(ConversionError::NoValidPostamble);
+    };
+    let source_hash = *source_hash;
+    let file_targets = convert_file_targets(&blocks, &mut chunks, source_hash)?;
+    let mut targets = convert_language_targets(&blocks, &mut chunks, lang, options, source_hash)?;
+    targets.extend(file_targets);
+    let has_default_code = blocks.iter().any(|block| {
+        matches!(
+            block,
+            SourceToplevelBlock::VerbatimBlock {
+                lang: block_lang,
+                chunk: None,
+                file: None,
+                ..
+            } if *block_lang == lang
+        )
+    });
+    let default_target = match &options.root_chunk {
+        Some(root_chunk) => Some(convert_root_chunk(
+            &mut chunks,
+            root_chunk,
+            lang,
+            source_hash,
+        )?),
+        None if has_default_code || targets.is_empty() => Some(convert_default_target(
+            blocks,
+            &mut chunks,
+            lang,
+            source_name,
+            options,
+        )?),
+        None => None,
+    };
+    // A document may hold several root chunks, of which only the chosen one
+    // is expanded.
+    if options.root_chunk.is_none() {
+        chunks.ensure_all_used()?;
+    }
+    if let Some(default_target) = default_target {
+        targets.insert(0, default_target);
+    }
+    Ok(targets)
+}
+
+/// Generates every unnamed code block in `lang` without a `file` attribute
+/// in document order, interleaved with the prose, followed by a test module
+/// for the blocks marked as tests. With `options.modules`, Rust output is
+/// wrapped in the modules opened by module headings, each getting a test
+/// module of its own, and with `options.rustdoc` its prose is written as doc
+/// comments.
+fn convert_default_target(
+    blocks: Vec<SourceToplevelBlock>,
+    chunks: &mut ChunkTable,
+    lang: GeneratedLanguage,
+    source_name: &str,
+    options: &TangleOptions,
+) -> Result<GeneratedTarget, ConversionError> {
+    let mut target = GeneratedTarget::new(None, lang);
+    let use_modules = options.modules && lang == GeneratedLanguage::Rust;
+    let rustdoc = options.rustdoc && lang == GeneratedLanguage::Rust;
+    let doc_comments = if rustdoc {
+        place_doc_comments(&blocks, lang, use_modules)
+    } else {
+        vec![]
+    };
+    // The heading levels of the modules currently open, innermost last,
+    // along with the tests found in each so far.
+    let mut open_modules: Vec<(u16, Vec<TestFunction>)> = vec![];
+    let mut file_tests = vec![];
+    for (index, block) in blocks.into_iter().enumerate() {
         match block {
             SourceToplevelBlock::Preamble { lang } => {
-                assert!(matches!(lang, SourceLanguage::Djot));
-                generated.push(GeneratedLineGroup::Preamble);
-            }
-            SourceToplevelBlock::VerbatimBlock { lang, lines } => {
-                assert!(matches!(lang, GeneratedLanguage::Rust));
-                generated.push(GeneratedLineGroup::CodeLineGroup(lines));
-            }
-            SourceToplevelBlock::LiterateBlock { lines } => {
-                generated.push(GeneratedLineGroup::CodeLineGroup(
-                    lines
-                        .into_iter()
-                        .map(|markup| format!("// {markup}"))
-                        .collect(),
+                if !matches!(lang, SourceLanguage::Djot) {
+                    return Err(ConversionError::UnsupportedSourceLanguage);
+                }
+            }
+            // Named chunks only appear where they are referenced.
+            SourceToplevelBlock::VerbatimBlock { chunk: Some(_), .. }
+            | SourceToplevelBlock::VerbatimBlock { file: Some(_), .. } => {}
+            SourceToplevelBlock::VerbatimBlock {
+                lang: block_lang, ..
+            } if block_lang != lang => {}
+            SourceToplevelBlock::VerbatimBlock {
+                lines: source_lines,
+                first_line,
+                test: Some(test),
+                ..
+            } => {
+                let origins = code_line_origins(first_line, source_lines.len());
+                let (lines, origins) = chunks.expand(&source_lines, &origins)?;
+                // The block becomes the body of a test function, where a
+                // test function of its own would only be declared.
+                let nested_test = lines
+                    .iter()
+                    .position(|line| line.trim_start().starts_with("#[test]"));
+                if let Some(index) = nested_test {
+                    return Err(ConversionError::NestedTestFunction {
+                        line: origins
+                            .get(index)
+                            .copied()
+                            .flatten()
+                            .map(|origin| origin.line),
+                    });
+                }
+                let tests = match open_modules.last_mut() {
+                    Some((_, tests)) => tests,
+                    None => &mut file_tests,
+                };
+                tests.push(TestFunction {
+                    test,
+                    lines,
+                    origins,
+                    first_line,
+                });
+            }
+            SourceToplevelBlock::VerbatimBlock {
+                lines: source_lines,
+                span,
+                chunk: None,
+                file: None,
+                first_line,
+                test: None,
+                ..
+            } => {
+                let origins = code_line_origins(first_line, source_lines.len());
+                let (lines, origins) = chunks.expand(&source_lines, &origins)?;
+                match span {
+                    Some(span) if options.block_markers => {
+                        let marker = BlockMarker {
+                            index,
+                            span,
+                            hash: utils::content_hash(source_lines.join("\n")),
+                        };
+                        target.push(
+                            GeneratedLineGroup::MarkedCodeLineGroup { marker, lines },
+                            origins,
+                        );
+                    }
+                    _ => target.push(GeneratedLineGroup::CodeLineGroup(lines), origins),
+                }
+            }
+            SourceToplevelBlock::LiterateBlock { lines, span } => {
+                let doc_comment = doc_comments.get(index).copied().flatten();
+                let doc_lines = match doc_comment {
+                    Some(doc_comment) => rustdoc_lines(&lines, doc_comment)?,
+                    None => vec![],
+                };
+                let (mut lines, comment_start_width) = match doc_comment {
+                    Some(doc_comment) if !doc_lines.is_empty() => {
+                        (doc_lines, doc_comment.start_width())
+                    }
+                    _ => (
+                        lines
+                            .iter()
+                            .map(|markup| lang.comment_line(markup))
+                            .collect(),
+                        lang.comment_start_width(),
+                    ),
+                };
+                // Converted prose may take more or fewer lines than its
+                // source, so its lines are mapped one to one as far as they go.
+                let mut origins: Vec<_> = (0..lines.len())
+                    .map(|offset| {
+                        span.as_ref().map(|span| LineOrigin {
+                            line: span.lines.start + offset.min(span.lines.len() - 1),
+                            column: 1,
+                            generated_column: comment_start_width + 1,
+                        })
+                    })
+                    .collect();
+                // Rustdoc joins consecutive doc comments across blank lines,
+                // so an empty doc line keeps the prose blocks apart.
+                if let Some(doc_comment) = doc_comment {
+                    if follows_doc_comment(&target) {
+                        lines.insert(0, doc_comment.comment_line(""));
+                        origins.insert(0, None);
+                    }
+                }
+                target.push(GeneratedLineGroup::CodeLineGroup(lines), origins);
+            }
+            SourceToplevelBlock::Heading {
+                level,
+                module,
+                line,
+            } => {
+                if !use_modules {
+                    continue;
+                }
+                close_modules(&mut target, &mut open_modules, level, source_name);
+                let Some(HeadingModule { name, is_pub }) = module else {
+                    continue;
+                };
+                let origin = LineOrigin {
+                    line,
+                    column: 1,
+                    generated_column: 1,
+                };
+                target.push(
+                    GeneratedLineGroup::CodeLineGroup(vec![module_start_line(&name, is_pub)]),
+                    vec![Some(origin)],
+                );
+                open_modules.push((level, vec![]));
+            }
+            SourceToplevelBlock::Postamble { source_hash } => {
+                close_modules(&mut target, &mut open_modules, 0, source_name);
+                push_test_module(&mut target, mem::take(&mut file_tests), source_name);
+                target.push(postamble(lang, source_hash, rustdoc), vec![]);
+            }
+        }
+    }
+    Ok(target)
+}
+
+/// Decides, for every literate block, which doc comment its prose becomes in
+/// rustdoc mode. Prose ahead of the first code of the file or of a module
+/// documents that file or module, and other prose documents the item that
+/// follows it. Prose that no item follows, such as prose at the end of the
+/// document, stays a plain comment (`None`).
+fn place_doc_comments(
+    blocks: &[SourceToplevelBlock],
+    lang: GeneratedLanguage,
+    use_modules: bool,
+) -> Vec<Option<DocComment>> {
+    let mut doc_comments = vec![None; blocks.len()];
+    let mut at_module_start = true;
+    let mut open_modules: Vec<u16> = vec![];
+    for (index, block) in blocks.iter().enumerate() {
+        match block {
+            SourceToplevelBlock::LiterateBlock { .. } => {
+                doc_comments[index] = if at_module_start {
+                    Some(DocComment::Inner)
+                } else if documents_next_item(
+                    &blocks[index + 1..],
+                    lang,
+                    use_modules,
+                    open_modules.last().copied(),
+                ) {
+                    Some(DocComment::Outer)
+                } else {
+                    None
+                };
+            }
+            SourceToplevelBlock::VerbatimBlock {
+                lang: block_lang,
+                chunk: None,
+                file: None,
+                test: None,
+                ..
+            } if *block_lang == lang => at_module_start = false,
+            SourceToplevelBlock::Heading { level, module, .. } if use_modules => {
+                while open_modules
+                    .last()
+                    .is_some_and(|open_level| open_level >= level)
+                {
+                    open_modules.pop();
+                    // The closed module is an item of the enclosing one.
+                    at_module_start = false;
+                }
+                if module.is_some() {
+                    open_modules.push(*level);
+                    at_module_start = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    doc_comments
+}
+
+/// Whether the first thing generated from `blocks`, skipping prose, is an
+/// item an outer doc comment can be attached to. A module heading only
+/// qualifies if it opens a module without closing the innermost open one,
+/// and any other heading closing that module ends the search.
+fn documents_next_item(
+    blocks: &[SourceToplevelBlock],
+    lang: GeneratedLanguage,
+    use_modules: bool,
+    innermost_module: Option<u16>,
+) -> bool {
+    for block in blocks {
+        match block {
+            SourceToplevelBlock::VerbatimBlock {
+                lang: block_lang,
+                lines,
+                chunk: None,
+                file: None,
+                test: None,
+                ..
+            } if *block_lang == lang => {
+                // Inner attributes and doc comments belong to the enclosing
+                // file or module instead.
+                return lines
+                    .iter()
+                    .map(|line| line.trim_start())
+                    .find(|line| !line.is_empty())
+                    .is_some_and(|line| !line.starts_with("#![") && !line.starts_with("//!"));
+            }
+            SourceToplevelBlock::Heading { level, module, .. } if use_modules => {
+                if innermost_module.is_some_and(|open_level| *level <= open_level) {
+                    return false;
+                }
+                if module.is_some() {
+                    return true;
+                }
+            }
+            SourceToplevelBlock::Postamble { .. } => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Whether the last group of `target` is prose written as doc comments.
+fn follows_doc_comment(target: &GeneratedTarget) -> bool {
+    match target.line_groups.last() {
+        Some(GeneratedLineGroup::CodeLineGroup(lines)) => lines
+            .last()
+            .is_some_and(|line| DocComment::uncomment_line(line).is_some()),
+        _ => false,
+    }
+}
+
+/// Converts Djot prose to the Markdown that rustdoc reads, as doc comment
+/// lines.
+fn rustdoc_lines(
+    lines: &[String],
+    doc_comment: DocComment,
+) -> Result<Vec<String>, ConversionError> {
+    let mut markdown = weave::convert_source_text(&lines.join("\n"), None)?;
+    tag_doc_code_blocks(&mut markdown);
+    let start = markdown.iter().position(|line| !line.is_empty());
+    let end = markdown.iter().rposition(|line| !line.is_empty());
+    let (Some(start), Some(end)) = (start, end) else {
+        return Ok(vec![]);
+    };
+    Ok(markdown[start..=end]
+        .iter()
+        .map(|line| doc_comment.comment_line(line))
+        .collect())
+}
+
+/// Tags the code blocks of prose converted for rustdoc as `text`, unless
+/// they are Rust, since rustdoc takes untagged blocks for doctests. The
+/// fences may be prefixed by block quotes and list indentation.
+fn tag_doc_code_blocks(markdown: &mut [String]) {
+    let mut open_fence_len = None;
+    for line in markdown {
+        let content = line.trim_start_matches([' ', '>']);
+        let fence_len = content.chars().take_while(|&ch| ch == '`').count();
+        if fence_len < 3 {
+            continue;
+        }
+        let info = content[fence_len..].trim();
+        match open_fence_len {
+            Some(open_len) if fence_len >= open_len && info.is_empty() => open_fence_len = None,
+            Some(_) => {}
+            None => {
+                open_fence_len = Some(fence_len);
+                if info.split_whitespace().next() != Some("rust") {
+                    let prefix_len = line.len() - content.len();
+                    *line = format!("{}{}text", &line[..prefix_len], &content[..fence_len]);
+                }
+            }
+        }
+    }
+}
+
+/// Closes the open modules whose headings are at `level` or deeper, each
+/// after the test module of its tests.
+fn close_modules(
+    target: &mut GeneratedTarget,
+    open_modules: &mut Vec<(u16, Vec<TestFunction>)>,
+    level: u16,
+    source_name: &str,
+) {
+    while let Some((_, tests)) = open_modules.pop_if(|(open_level, _)| *open_level >= level) {
+        push_test_module(target, tests, source_name);
+        target.push(
+            GeneratedLineGroup::CodeLineGroup(vec!["}".to_owned()]),
+            vec![None],
+        );
+    }
+}
+
+/// A code block marked as a test or an example, waiting for the test
+/// module of the file or module it is in.
+struct TestFunction {
+    test: TestBlock,
+    lines: Vec<String>,
+    origins: Vec<Option<LineOrigin>>,
+    first_line: Option<usize>,
+}
+
+/// Writes a `#[cfg(test)]` module with a `#[test]` function for each test,
+/// or nothing if there are none. Tests sharing a name are told apart by a
+/// numeric suffix.
+///
+/// A block defining `main` gets a call to it at the end of its test.
+///
+/// An example runs in a child process of the test binary, and what it
+/// writes to stdout is compared with the output documented for it. When
+/// they differ, the test fails with a line diff naming the lines of
+/// `source_name` that hold the example and its output.
+fn push_test_module(target: &mut GeneratedTarget, tests: Vec<TestFunction>, source_name: &str) {
+    if tests.is_empty() {
+        return;
+    }
+    let mut lines = vec![
+        "#[cfg(test)]".to_owned(),
+        "mod tests {".to_owned(),
+        "    #[allow(unused_imports)]".to_owned(),
+        "    use super::*;".to_owned(),
+    ];
+    if tests.iter().any(|test| test.test.expected_output.is_some()) {
+        lines.push(String::new());
+        lines.extend(RUN_EXAMPLE_FUNCTION.iter().map(|line| line.to_string()));
+        lines.push(String::new());
+        lines.extend(CHECK_OUTPUT_FUNCTION.iter().map(|line| line.to_string()));
+    }
+    let mut origins = vec![None; lines.len()];
+    let mut names = HashSet::new();
+    for TestFunction {
+        test,
+        lines: body,
+        origins: body_origins,
+        first_line,
+    } in tests
+    {
+        let mut name = test.name.clone();
+        for suffix in 2.. {
+            if names.insert(name.clone()) {
+                break;
+            }
+            name = format!("{}_{suffix}", test.name);
+        }
+        lines.extend([
+            String::new(),
+            "    #[test]".to_owned(),
+            format!("    fn {name}() {{"),
+        ]);
+        origins.extend([None, None, None]);
+        let indent = match &test.expected_output {
+            Some(_) => {
+                lines.push(format!(
+                    "        let Some(output) = run_example(module_path!(), {name:?}, || {{"
                 ));
+                origins.push(None);
+                EXAMPLE_BODY_INDENT
+            }
+            None => TEST_BODY_INDENT,
+        };
+        for (line, origin) in body.iter().zip(origins_for(&body_origins, body.len())) {
+            lines.push(if line.is_empty() {
+                String::new()
+            } else {
+                format!("{indent}{line}")
+            });
+            origins.push(origin.map(|origin| LineOrigin {
+                generated_column: origin.generated_column + indent.len(),
+                ..origin
+            }));
+        }
+        // A block that defines `main`, as a complete example does, only
+        // declares it inside the test function, so it is called as well.
+        if let Some(main) = body.iter().find(|line| line.contains("fn main(")) {
+            lines.push(if main.contains("->") {
+                format!("{indent}main().unwrap();")
+            } else {
+                format!("{indent}main();")
+            });
+            origins.push(None);
+        }
+        if let Some(expected_output) = &test.expected_output {
+            let example_location = match first_line {
+                Some(first_line) => format!("{source_name}:{first_line}"),
+                None => source_name.to_owned(),
+            };
+            lines.extend([
+                "        }) else {".to_owned(),
+                "            return;".to_owned(),
+                "        };".to_owned(),
+            ]);
+            lines.push(format!(
+                "        check_output(&output, {:?}, {:?}, {:?});",
+                expected_output.lines.join("\n"),
+                example_location,
+                format!("{source_name}:{}", expected_output.first_line),
+            ));
+            origins.extend([None, None, None, None]);
+        }
+        lines.push("    }".to_owned());
+        origins.push(None);
+    }
+    lines.push("}".to_owned());
+    origins.push(None);
+    target.push(GeneratedLineGroup::CodeLineGroup(lines), origins);
+}
+
+const TEST_BODY_INDENT: &str = "        ";
+const EXAMPLE_BODY_INDENT: &str = "            ";
+
+/// Runs an example in a child process with its stdout captured, so that
+/// anything writing to stdout counts as its output, not just `print!`.
+const RUN_EXAMPLE_FUNCTION: &[&str] = &[
+    "    /// Runs an example in a child process of the test binary, returning",
+    "    /// what it wrote to stdout. In that child, runs the example itself and",
+    "    /// returns `None`.",
+    "    fn run_example(module: &str, name: &str, example: impl FnOnce()) -> Option<String> {",
+    "        const START: &str = \"\\n[LITWEB example output start]\\n\";",
+    "        const END: &str = \"\\n[LITWEB example output end]\\n\";",
+    "        let module = module.split_once(\"::\").map_or(\"\", |(_, module)| module);",
+    "        let test = format!(\"{module}::{name}\");",
+    "        if std::env::var_os(\"LITWEB_EXAMPLE\").is_some_and(|running| running == test.as_str()) {",
+    "            use std::io::Write;",
+    "            print!(\"{START}\");",
+    "            std::io::stdout().flush().unwrap();",
+    "            example();",
+    "            std::io::stdout().flush().unwrap();",
+    "            print!(\"{END}\");",
+    "            std::io::stdout().flush().unwrap();",
+    "            return None;",
+    "        }",
+    "        let child = std::process::Command::new(std::env::current_exe().unwrap())",
+    "            .args([test.as_str(), \"--exact\", \"--nocapture\"])",
+    "            .env(\"LITWEB_EXAMPLE\", &test)",
+    "            .output()",
+    "            .unwrap();",
+    "        let stdout = String::from_utf8_lossy(&child.stdout);",
+    "        let stderr = String::from_utf8_lossy(&child.stderr);",
+    "        let output = stdout",
+    "            .split_once(START)",
+    "            .and_then(|(_, output)| output.split_once(END))",
+    "            .map(|(output, _)| output.to_owned());",
+    "        match output {",
+    "            Some(output) if child.status.success() => Some(output),",
+    "            _ => panic!(\"example {test} failed ({})\\n{stdout}{stderr}\", child.status),",
+    "        }",
+    "    }",
+];
+
+/// Compares what an example printed with its documented output, line by
+/// line and ignoring trailing whitespace.
+const CHECK_OUTPUT_FUNCTION: &[&str] = &[
+    "    #[track_caller]",
+    "    fn check_output(printed: &str, documented: &str, example: &str, output: &str) {",
+    "        let printed: Vec<&str> = printed.trim_end().lines().map(str::trim_end).collect();",
+    "        let documented: Vec<&str> = documented.lines().map(str::trim_end).collect();",
+    "        if printed == documented {",
+    "            return;",
+    "        }",
+    "        let mut diff = String::new();",
+    "        for index in 0..printed.len().max(documented.len()) {",
+    "            match (documented.get(index), printed.get(index)) {",
+    "                (Some(documented), Some(printed)) if documented == printed => {",
+    "                    diff.push_str(&format!(\"  {documented}\\n\"));",
+    "                }",
+    "                (documented, printed) => {",
+    "                    if let Some(documented) = documented {",
+    "                        diff.push_str(&format!(\"- {documented}\\n\"));",
+    "                    }",
+    "                    if let Some(printed) = printed {",
+    "                        diff.push_str(&format!(\"+ {printed}\\n\"));",
+    "                    }",
+    "                }",
+    "            }",
+    "        }",
+    "        panic!(",
+    "            \"example at {example} printed other output than documented at {output}\\n\\",
+    "             (- documented, + printed)\\n{diff}\"",
+    "        );",
+    "    }",
+];
+
+/// Generates the expansion of a single named chunk, leaving out prose and
+/// unnamed code blocks.
+fn convert_root_chunk(
+    chunks: &mut ChunkTable,
+    root_chunk: &str,
+    lang: GeneratedLanguage,
+    source_hash: Option<u64>,
+) -> Result<GeneratedTarget, ConversionError> {
+    let (lines, origins) = chunks.expand(&[format!("<<{root_chunk}>>")], &[None])?;
+    let mut target = GeneratedTarget::new(None, lang);
+    target.push(GeneratedLineGroup::CodeLineGroup(lines), origins);
+    target.push(postamble(lang, source_hash, false), vec![]);
+    Ok(target)
+}
+
+/// Generates the unnamed code blocks in languages other than `lang` without
+/// a `file` attribute, into one output per language. Prose stays in the
+/// default output.
+fn convert_language_targets(
+    blocks: &[SourceToplevelBlock],
+    chunks: &mut ChunkTable,
+    lang: GeneratedLanguage,
+    options: &TangleOptions,
+    source_hash: Option<u64>,
+) -> Result<Vec<GeneratedTarget>, ConversionError> {
+    let mut targets = vec![];
+    for (index, block) in blocks.iter().enumerate() {
+        let SourceToplevelBlock::VerbatimBlock {
+            lang: block_lang,
+            lines: source_lines,
+            span,
+            chunk: None,
+            file: None,
+            first_line,
+            ..
+        } = block
+        else {
+            continue;
+        };
+        if *block_lang == lang {
+            continue;
+        }
+        let origins = code_line_origins(*first_line, source_lines.len());
+        let (lines, origins) = chunks.expand(source_lines, &origins)?;
+        let group = match span {
+            Some(span) if options.block_markers => {
+                let marker = BlockMarker {
+                    index,
+                    span: span.clone(),
+                    hash: utils::content_hash(source_lines.join("\n")),
+                };
+                GeneratedLineGroup::MarkedCodeLineGroup { marker, lines }
+            }
+            _ => GeneratedLineGroup::CodeLineGroup(lines),
+        };
+        target_for(&mut targets, None, *block_lang).push(group, origins);
+    }
+    for target in &mut targets {
+        let lang = target.lang;
+        target.push(postamble(lang, source_hash, false), vec![]);
+    }
+    Ok(targets)
+}
+
+/// Generates the files named by `file` attributes, in the order they first
+/// appear. A named chunk is expanded once, where its first block naming the
+/// file appears. Each file is in the language of the first block naming it.
+fn convert_file_targets(
+    blocks: &[SourceToplevelBlock],
+    chunks: &mut ChunkTable,
+    source_hash: Option<u64>,
+) -> Result<Vec<GeneratedTarget>, ConversionError> {
+    let mut targets = vec![];
+    let mut expanded_chunks = HashSet::new();
+    for block in blocks {
+        let SourceToplevelBlock::VerbatimBlock {
+            lang,
+            lines,
+            chunk,
+            file: Some(file),
+            first_line,
+            ..
+        } = block
+        else {
+            continue;
+        };
+        // Files are written next to the document, and never outside its
+        // directory. Paths naming the same file, such as `./a.rs` and
+        // `a.rs`, name the same target.
+        let is_contained = Path::new(file)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        let normalized: PathBuf = Path::new(file)
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        if !is_contained || normalized.as_os_str().is_empty() {
+            return Err(ConversionError::OutputPathOutsideSourceDir {
+                file: file.clone(),
+                // The opening fence of the block.
+                line: first_line.map(|line| line - 1),
+            });
+        }
+        let file = &normalized.to_string_lossy().into_owned();
+        let (lines, origins) = match chunk {
+            Some(name) if !expanded_chunks.insert(name) => continue,
+            Some(name) => chunks.expand(&[format!("<<{name}>>")], &[None])?,
+            None => chunks.expand(lines, &code_line_origins(*first_line, lines.len()))?,
+        };
+        target_for(&mut targets, Some(file), *lang)
+            .push(GeneratedLineGroup::CodeLineGroup(lines), origins);
+    }
+    for target in &mut targets {
+        let lang = target.lang;
+        target.push(postamble(lang, source_hash, false), vec![]);
+    }
+    Ok(targets)
+}
+
+/// Finds the target for `file`, or for the default output of `lang` if
+/// `file` is `None`, adding it if it is not there yet.
+fn target_for<'t>(
+    targets: &'t mut Vec<GeneratedTarget>,
+    file: Option<&String>,
+    lang: GeneratedLanguage,
+) -> &'t mut GeneratedTarget {
+    let position = targets.iter().position(|target| match file {
+        Some(_) => target.file.as_ref() == file,
+        None => target.file.is_none() && target.lang == lang,
+    });
+    match position {
+        Some(position) => &mut targets[position],
+        None => {
+            targets.push(GeneratedTarget::new(file.cloned(), lang));
+            targets.last_mut().unwrap()
+        }
+    }
+}
+
+fn postamble(
+    lang: GeneratedLanguage,
+    source_hash: Option<u64>,
+    rustdoc: bool,
+) -> GeneratedLineGroup {
+    GeneratedLineGroup::Postamble {
+        source_lang: SourceLanguage::Djot,
+        signature: GeneratedSignature {
+            lang,
+            source_hash,
+            generated_hash: None,
+            rustdoc,
+        },
+    }
+}
+
+fn code_line_origins(first_line: Option<usize>, line_count: usize) -> Vec<Option<LineOrigin>> {
+    (0..line_count)
+        .map(|offset| {
+            first_line.map(|first_line| LineOrigin {
+                line: first_line + offset,
+                column: 1,
+                generated_column: 1,
+            })
+        })
+        .collect()
+}
+
+/// The named chunks of a source, i.e. its code blocks carrying a `chunk`
+/// attribute, which are spliced into other code blocks wherever a line
+/// consists of a `<<name>>` reference. A chunk may be defined in several
+/// blocks, whose lines are joined in document order, and may be referenced
+/// before its first definition.
+struct ChunkTable {
+    definitions: Vec<ChunkDefinition>,
+    used: HashSet<String>,
+}
+
+struct ChunkDefinition {
+    name: String,
+    lines: Vec<String>,
+    origins: Vec<Option<LineOrigin>>,
+}
+
+impl ChunkTable {
+    fn collect(blocks: &[SourceToplevelBlock]) -> Result<Self, ConversionError> {
+        let mut definitions: Vec<ChunkDefinition> = vec![];
+        for block in blocks {
+            let SourceToplevelBlock::VerbatimBlock {
+                lines,
+                chunk: Some(name),
+                first_line,
+                ..
+            } = block
+            else {
+                continue;
+            };
+            let origins = code_line_origins(*first_line, lines.len());
+            // Later definitions of a chunk append to it.
+            match definitions
+                .iter_mut()
+                .find(|definition| definition.name == *name)
+            {
+                Some(definition) => {
+                    definition.lines.push(String::new());
+                    definition.lines.extend(lines.iter().cloned());
+                    definition.origins.push(None);
+                    definition.origins.extend(origins);
+                }
+                None => definitions.push(ChunkDefinition {
+                    name: name.clone(),
+                    lines: lines.clone(),
+                    origins,
+                }),
             }
-            SourceToplevelBlock::Postamble => {
-                generated.push(GeneratedLineGroup::Postamble {
-                    source_lang: SourceLanguage::Djot,
+        }
+        Ok(ChunkTable {
+            definitions,
+            used: HashSet::new(),
+        })
+    }
+
+    fn expand(
+        &mut self,
+        lines: &[String],
+        origins: &[Option<LineOrigin>],
+    ) -> Result<(Vec<String>, Vec<Option<LineOrigin>>), ConversionError> {
+        let mut expanded = (vec![], vec![]);
+        self.expand_into(lines, origins, "", &mut vec![], &mut expanded)?;
+        Ok(expanded)
+    }
+
+    fn expand_into(
+        &mut self,
+        lines: &[String],
+        origins: &[Option<LineOrigin>],
+        indent: &str,
+        active: &mut Vec<String>,
+        expanded: &mut (Vec<String>, Vec<Option<LineOrigin>>),
+    ) -> Result<(), ConversionError> {
+        for (line, origin) in lines.iter().zip(origins_for(origins, lines.len())) {
+            let Some((reference_indent, name)) = parse_chunk_reference(line) else {
+                if line.is_empty() {
+                    expanded.0.push(String::new());
+                } else {
+                    expanded.0.push(format!("{indent}{line}"));
+                }
+                expanded.1.push(origin.map(|origin| LineOrigin {
+                    generated_column: origin.generated_column + indent.chars().count(),
+                    ..origin
+                }));
+                continue;
+            };
+            let line = origin.map(|origin| origin.line);
+            if active.iter().any(|active_name| active_name == name) {
+                return Err(ConversionError::CyclicChunkReference {
+                    name: name.to_owned(),
+                    line,
                 });
             }
+            let Some(definition) = self
+                .definitions
+                .iter()
+                .find(|definition| definition.name == name)
+            else {
+                return Err(ConversionError::UndefinedChunk {
+                    name: name.to_owned(),
+                    line,
+                });
+            };
+            let (chunk_lines, chunk_origins) =
+                (definition.lines.clone(), definition.origins.clone());
+            self.used.insert(name.to_owned());
+            active.push(name.to_owned());
+            self.expand_into(
+                &chunk_lines,
+                &chunk_origins,
+                &format!("{indent}{reference_indent}"),
+                active,
+                expanded,
+            )?;
+            active.pop();
+        }
+        Ok(())
+    }
+
+    fn ensure_all_used(&self) -> Result<(), ConversionError> {
+        match self
+            .definitions
+            .iter()
+            .find(|definition| !self.used.contains(&definition.name))
+        {
+            Some(definition) => Err(ConversionError::UnusedChunk {
+                name: definition.name.clone(),
+                // The fence of the first block defining the chunk.
+                line: definition
+                    .origins
+                    .first()
+                    .copied()
+                    .flatten()
+                    .map(|origin| origin.line - 1),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Picks the generated language of a document: Rust if it has any Rust code
+/// block, and otherwise the known language with the most code blocks, the
+/// first of them to appear winning a tie. Blocks in other languages, such as
+/// the `toml` of a script's dependencies, go to outputs of their own.
+pub(crate) fn detect_generated_language(source: &str) -> Option<GeneratedLanguage> {
+    let mut counts: Vec<(GeneratedLanguage, usize)> = vec![];
+    for event in DjotParser::new(source) {
+        let DjotEvent::Start(DjotContainer::CodeBlock { language }, _) = event else {
+            continue;
+        };
+        let Some(lang) = GeneratedLanguage::from_code_block_tag(language) else {
+            continue;
+        };
+        if lang == GeneratedLanguage::Rust {
+            return Some(lang);
+        }
+        match counts.iter_mut().find(|(counted, _)| *counted == lang) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((lang, 1)),
         }
     }
-    Ok(generated)
+    counts
+        .iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map(|(lang, _)| *lang)
 }
 
+/// Splits the source into top-level blocks. Code blocks in a known language
+/// become verbatim blocks, while everything else, including code blocks in
+/// other languages, is kept as literate markup.
+///
+/// Code blocks nested in lists, block quotes, divs or sections are pulled out
+/// as well, splitting the prose around them into separate literate blocks.
 pub(crate) fn analyze_source_blocks(
     mut reader: impl io::Read,
 ) -> Result<Vec<SourceToplevelBlock>, AnalysisError> {
@@ -69,59 +1052,367 @@ pub(crate) fn analyze_source_blocks(
     source_line_groups.push(SourceToplevelBlock::Preamble {
         lang: SourceLanguage::Djot,
     });
-    let top_level_block_iter =
-        std::iter::from_fn(|| pull_next_top_level_block(&mut parser)).enumerate();
-    for (_idx, (events, range)) in top_level_block_iter {
-        match events.get(0) {
-            Some(DjotEvent::Start(DjotContainer::CodeBlock { language }, ..))
-                if *language == "rust" =>
-            {
-                match events.last() {
-                    Some(DjotEvent::End(DjotContainer::CodeBlock { language }))
-                        if *language == "rust" => {}
-                    _ => return Err(AnalysisError::InvalidDjotBlock),
-                }
-                let events_count = events.len();
-                let inner_events_range = 1..events_count - 1;
-                let mut lines = vec![];
-                for event in &events[inner_events_range] {
-                    match event {
-                        DjotEvent::Str(s) => {
-                            lines.push(s.trim_end().to_string());
-                        }
-                        _ => unreachable!(),
+    let top_level_block_iter = std::iter::from_fn(|| pull_next_top_level_block(&mut parser));
+    // The text of the last heading, which names the tests below it.
+    let mut heading = None;
+    // The levels of the headings with a module attribute whose sections are
+    // still open, innermost last.
+    let mut module_levels: Vec<u16> = vec![];
+    for (events, event_ranges, range) in top_level_block_iter {
+        // Blank lines between blocks are already represented by the
+        // separators between generated line groups.
+        if events
+            .iter()
+            .all(|event| matches!(event, DjotEvent::Blankline))
+        {
+            continue;
+        }
+        let mut prose_start = range.start;
+        // A module attribute on a section applies to its heading.
+        let mut section_module = None;
+        let mut index = 0;
+        while index < events.len() {
+            // Output blocks stay in the prose, besides documenting the
+            // example before them.
+            let is_output = matches!(
+                &events[index],
+                DjotEvent::Start(DjotContainer::CodeBlock { .. }, attributes)
+                    if has_class(attributes, "output")
+            );
+            let (code_block_len, lang) = match &events[index] {
+                DjotEvent::Start(DjotContainer::Section { .. }, attributes) => {
+                    section_module = module_attribute(attributes);
+                    (1, None)
+                }
+                DjotEvent::Start(DjotContainer::Heading { level, .. }, attributes) => {
+                    heading = Some(heading_text(&events[index..]));
+                    let module = module_attribute(attributes).or_else(|| section_module.take());
+                    let closes_module = module_levels.last().is_some_and(|open| open >= level);
+                    module_levels.retain(|open| open < level);
+                    if module.is_some() {
+                        module_levels.push(*level);
+                    }
+                    if module.is_some() || closes_module {
+                        let heading_start = source[..event_ranges[index].start]
+                            .rfind('\n')
+                            .map_or(0, |newline| newline + 1)
+                            .max(prose_start);
+                        push_prose_block(
+                            source,
+                            prose_start..heading_start,
+                            &mut source_line_groups,
+                        );
+                        prose_start = heading_start;
+                        source_line_groups.push(SourceToplevelBlock::Heading {
+                            level: *level,
+                            module,
+                            line: source[..heading_start].matches('\n').count() + 1,
+                        });
                     }
+                    (1, None)
                 }
-                source_line_groups.push(SourceToplevelBlock::VerbatimBlock {
-                    lang: GeneratedLanguage::Rust,
-                    lines: lines,
-                });
+                DjotEvent::Start(DjotContainer::CodeBlock { language }, _) => {
+                    let code_block_end = events[index..].iter().position(|event| {
+                        matches!(event, DjotEvent::End(DjotContainer::CodeBlock { .. }))
+                    });
+                    let Some(code_block_len) = code_block_end else {
+                        return Err(AnalysisError::InvalidDjotBlock {
+                            offset: event_ranges[index].start,
+                        });
+                    };
+                    (
+                        code_block_len + 1,
+                        GeneratedLanguage::from_code_block_tag(language),
+                    )
+                }
+                _ => (1, None),
+            };
+            let code_block = index..index + code_block_len;
+            index += code_block_len;
+            let code_range = event_ranges[code_block.clone()]
+                .iter()
+                .cloned()
+                .reduce(union_range)
+                .unwrap_or(range.clone());
+            if is_output {
+                pair_expected_output(
+                    source,
+                    &events[code_block],
+                    code_range.clone(),
+                    &source[prose_start..code_range.start],
+                    heading.as_deref(),
+                    &mut source_line_groups,
+                )?;
+                continue;
+            }
+            let Some(lang) = lang else {
+                continue;
+            };
+            push_prose_block(
+                source,
+                prose_start..code_range.start,
+                &mut source_line_groups,
+            );
+            prose_start = code_range.end;
+            source_line_groups.push(analyze_code_block(
+                source,
+                &events[code_block],
+                code_range,
+                lang,
+                heading.as_deref(),
+            )?);
+        }
+        push_prose_block(source, prose_start..range.end, &mut source_line_groups);
+    }
+    source_line_groups.push(SourceToplevelBlock::Postamble {
+        source_hash: Some(utils::content_hash(source)),
+    });
+    Ok(source_line_groups)
+}
+
+/// Reads the module name from a `mod` attribute, along with whether the
+/// module is public, as marked by a `pub` class.
+fn module_attribute(attributes: &DjotAttributes) -> Option<HeadingModule> {
+    Some(HeadingModule {
+        name: attributes.get("mod")?.to_string(),
+        is_pub: has_class(attributes, "pub"),
+    })
+}
+
+fn has_class(attributes: &DjotAttributes, class: &str) -> bool {
+    attributes.get("class").is_some_and(|classes| {
+        classes
+            .to_string()
+            .split_whitespace()
+            .any(|other| other == class)
+    })
+}
+
+/// The text of the heading whose start event `events` begins with.
+fn heading_text(events: &[DjotEvent]) -> String {
+    let mut text = String::new();
+    for event in &events[1..] {
+        match event {
+            DjotEvent::End(DjotContainer::Heading { .. }) => break,
+            DjotEvent::Str(s) => text.push_str(s),
+            DjotEvent::Softbreak => text.push(' '),
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Names the test function of a Rust block marked as a test by a `test`
+/// class or attribute, after the value of the attribute, the id of the
+/// block or else the heading it is under.
+fn test_name(attributes: &DjotAttributes, heading: Option<&str>) -> Option<String> {
+    let test = attributes.get("test").map(|value| value.to_string());
+    if test.is_none() && !has_class(attributes, "test") {
+        return None;
+    }
+    let name = test
+        .filter(|value| !value.is_empty())
+        .or_else(|| attributes.get("id").map(|id| id.to_string()))
+        .or_else(|| heading.map(str::to_owned))
+        .unwrap_or_default();
+    Some(to_identifier(&name))
+}
+
+/// Pairs an output block with the Rust block right before it, turning that
+/// block into an example. A block not already marked as a test is named
+/// after the heading it is under.
+fn pair_expected_output(
+    source: &str,
+    events: &[DjotEvent],
+    range: Range<usize>,
+    preceding_text: &str,
+    heading: Option<&str>,
+    source_line_groups: &mut [SourceToplevelBlock],
+) -> Result<(), AnalysisError> {
+    // Only blank lines and the attributes of the output block may separate
+    // it from the example.
+    let is_adjacent = preceding_text.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with('{')
+    });
+    let test = match source_line_groups.last_mut() {
+        Some(SourceToplevelBlock::VerbatimBlock {
+            lang: GeneratedLanguage::Rust,
+            chunk: None,
+            file: None,
+            test,
+            ..
+        }) if is_adjacent => test,
+        _ => {
+            return Err(AnalysisError::UnpairedOutputBlock {
+                offset: range.start,
+            })
+        }
+    };
+    let (lines, first_line) = code_block_content(source, events, range)?;
+    test.get_or_insert_with(|| TestBlock {
+        name: to_identifier(heading.unwrap_or_default()),
+        expected_output: None,
+    })
+    .expected_output = Some(ExpectedOutput { lines, first_line });
+    Ok(())
+}
+
+/// Turns text such as a heading into a snake case identifier, e.g.
+/// `Parsing a header` into `parsing_a_header`.
+fn to_identifier(text: &str) -> String {
+    let words = text
+        .split(|ch: char| !ch.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    let identifier = words.join("_");
+    if identifier.is_empty() {
+        "example".to_owned()
+    } else if identifier.starts_with(|ch: char| ch.is_numeric())
+        || RUST_KEYWORDS.contains(&identifier.as_str())
+    {
+        format!("test_{identifier}")
+    } else {
+        identifier
+    }
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+    "pub", "ref", "return", "self", "static", "struct", "super", "trait", "true", "type", "unsafe",
+    "use", "where", "while", "abstract", "become", "box", "do", "final", "macro", "override",
+    "priv", "try", "typeof", "unsized", "virtual", "yield",
+];
+
+/// Turns the events of a code block in a known language, from its start
+/// through its end, into a verbatim block. `heading` is the text of the
+/// heading the block is under, if any.
+fn analyze_code_block(
+    source: &str,
+    events: &[DjotEvent],
+    range: Range<usize>,
+    lang: GeneratedLanguage,
+    heading: Option<&str>,
+) -> Result<SourceToplevelBlock, AnalysisError> {
+    let span = SourceSpan::from_byte_range(source, range.clone());
+    let Some(DjotEvent::Start(DjotContainer::CodeBlock { .. }, attributes)) = events.first() else {
+        return Err(AnalysisError::InvalidDjotBlock {
+            offset: range.start,
+        });
+    };
+    let chunk = attributes.get("chunk").map(|name| name.to_string());
+    let file = attributes.get("file").map(|path| path.to_string());
+    // Chunks and blocks going to files of their own are never tests.
+    let test = match (lang, &chunk, &file) {
+        (GeneratedLanguage::Rust, None, None) => {
+            test_name(attributes, heading).map(|name| TestBlock {
+                name,
+                expected_output: None,
+            })
+        }
+        _ => None,
+    };
+    let (lines, first_line) = code_block_content(source, events, range)?;
+    Ok(SourceToplevelBlock::VerbatimBlock {
+        lang,
+        lines,
+        span: Some(span),
+        chunk,
+        file,
+        first_line: Some(first_line),
+        test,
+    })
+}
+
+/// Reads the lines of a code block from its events, from its start through
+/// its end, along with the source line the first of them is on.
+fn code_block_content(
+    source: &str,
+    events: &[DjotEvent],
+    range: Range<usize>,
+) -> Result<(Vec<String>, usize), AnalysisError> {
+    match (events.first(), events.last()) {
+        (
+            Some(DjotEvent::Start(DjotContainer::CodeBlock { language }, _)),
+            Some(DjotEvent::End(DjotContainer::CodeBlock {
+                language: end_language,
+            })),
+        ) if end_language == language && events.len() >= 2 => {}
+        _ => {
+            return Err(AnalysisError::InvalidDjotBlock {
+                offset: range.start,
+            })
+        }
+    }
+    let mut lines = vec![];
+    for event in &events[1..events.len() - 1] {
+        match event {
+            DjotEvent::Str(s) => {
+                lines.push(s.trim_end().to_string());
             }
             _ => {
-                let block_source = &source[range];
-                source_line_groups.push(SourceToplevelBlock::LiterateBlock {
-                    lines: block_source
-                        .lines()
-                        .map(|s| s.trim_end().to_string())
-                        .collect(),
-                });
+                return Err(AnalysisError::UnexpectedCodeBlockContent {
+                    offset: range.start,
+                })
             }
         }
     }
-    source_line_groups.push(SourceToplevelBlock::Postamble);
-    Ok(source_line_groups)
+    // The code starts on the line after the opening fence.
+    let start_line = source[..range.start].matches('\n').count() + 1;
+    let fence_offset = source[range]
+        .lines()
+        .position(|line| line.trim_start().starts_with("```"))
+        .unwrap_or(0);
+    Ok((lines, start_line + fence_offset + 1))
+}
+
+/// Adds the prose in `range` as a literate block, leaving out the blank
+/// lines around it, or nothing if it is blank.
+fn push_prose_block(
+    source: &str,
+    range: Range<usize>,
+    source_line_groups: &mut Vec<SourceToplevelBlock>,
+) {
+    let text = source[range.clone()].trim_end();
+    let leading_blank_len: usize = text
+        .split_inclusive('\n')
+        .take_while(|line| line.trim().is_empty())
+        .map(str::len)
+        .sum();
+    let prose = range.start + leading_blank_len..range.start + text.len();
+    if prose.is_empty() {
+        return;
+    }
+    source_line_groups.push(SourceToplevelBlock::LiterateBlock {
+        lines: source[prose.clone()]
+            .lines()
+            .map(|s| s.trim_end().to_string())
+            .collect(),
+        span: Some(SourceSpan::from_byte_range(source, prose)),
+    });
 }
 
+/// The events of a top-level block, along with the range of each event and
+/// of the whole block.
+type TopLevelBlock<'input> = (Vec<DjotEvent<'input>>, Vec<Range<usize>>, Range<usize>);
+
 fn pull_next_top_level_block<'input>(
     parser: &mut DjotParserWithOffset<'input>,
-) -> Option<(Vec<DjotEvent<'input>>, Range<usize>)> {
+) -> Option<TopLevelBlock<'input>> {
     let mut parser = parser.peekable();
     let (first_token, first_token_range) = parser.next()?;
     match &first_token {
-        _ if is_singleton_block(&first_token) => Some((vec![first_token], first_token_range)),
+        _ if is_singleton_block(&first_token) => Some((
+            vec![first_token],
+            vec![first_token_range.clone()],
+            first_token_range,
+        )),
         DjotEvent::Start(start_container, _) if is_block_container(start_container) => {
             let mut nesting_counter = 1usize;
             let mut tokens = vec![first_token.clone()];
+            let mut token_ranges = vec![first_token_range.clone()];
             let mut total_range = first_token_range;
             while let Some((next_token, next_token_range)) = parser.next() {
                 match &next_token {
@@ -134,12 +1425,13 @@ fn pull_next_top_level_block<'input>(
                     _ => {}
                 }
                 tokens.push(next_token);
+                token_ranges.push(next_token_range.clone());
                 total_range = union_range(total_range, next_token_range);
                 if nesting_counter == 0 {
                     break;
                 }
             }
-            Some((tokens, total_range))
+            Some((tokens, token_ranges, total_range))
         }
         _ => None,
     }
@@ -210,21 +1502,321 @@ fn union_range(lhs: Range<usize>, rhs: Range<usize>) -> Range<usize> {
     start..end
 }
 
+use crate::tangle_and_untangle::BlockMarker;
+use crate::tangle_and_untangle::DocComment;
+use crate::tangle_and_untangle::ExpectedOutput;
 use crate::tangle_and_untangle::GeneratedLanguage;
 use crate::tangle_and_untangle::GeneratedLineGroup;
+use crate::tangle_and_untangle::GeneratedSignature;
+use crate::tangle_and_untangle::GeneratedTarget;
+use crate::tangle_and_untangle::HeadingModule;
+use crate::tangle_and_untangle::LineOrigin;
 use crate::tangle_and_untangle::SourceLanguage;
+use crate::tangle_and_untangle::SourceSpan;
 use crate::tangle_and_untangle::SourceToplevelBlock;
-use crate::tangle_and_untangle::GENERATED_SIGNATURE;
+use crate::tangle_and_untangle::TestBlock;
+
+use crate::tangle_and_untangle::module_start_line;
+use crate::tangle_and_untangle::parse_chunk_reference;
 
 use crate::tangle_and_untangle::AnalysisError;
 use crate::tangle_and_untangle::ConversionError;
 use crate::tangle_and_untangle::GenerationError;
 
+use crate::source_map::SourceMapping;
+use crate::utils;
+use crate::weave;
+use crate::TangleOptions;
+
+use std::collections::HashSet;
 use std::io;
+use std::io::Write;
 use std::mem;
 use std::ops::Range;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
 
+use jotdown::Attributes as DjotAttributes;
 use jotdown::Container as DjotContainer;
 use jotdown::Event as DjotEvent;
 use jotdown::OffsetIter as DjotParserWithOffset;
 use jotdown::Parser as DjotParser;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jotdown::CowStr;
+
+    /// Tangles `source` to Rust, returning the text of the default output.
+    fn tangle(source: &str, options: &TangleOptions) -> Result<String, ConversionError> {
+        let blocks = analyze_source_blocks(source.as_bytes()).unwrap();
+        let targets =
+            convert_source_blocks(blocks, GeneratedLanguage::Rust, "doc.lit.djot", options)?;
+        let mut generated = vec![];
+        generate_output(&mut generated, targets.into_iter().next().unwrap()).unwrap();
+        Ok(String::from_utf8(generated).unwrap())
+    }
+
+    #[test]
+    fn code_block_with_markup_is_rejected() {
+        let source = "```rust\nfn main() {}\n```\n";
+        let events = [
+            DjotEvent::Start(
+                DjotContainer::CodeBlock { language: "rust" },
+                DjotAttributes::default(),
+            ),
+            DjotEvent::Str(CowStr::Borrowed("fn main() {}")),
+            DjotEvent::Softbreak,
+            DjotEvent::End(DjotContainer::CodeBlock { language: "rust" }),
+        ];
+        let result = analyze_code_block(
+            source,
+            &events,
+            0..source.len(),
+            GeneratedLanguage::Rust,
+            None,
+        );
+        assert!(matches!(
+            result,
+            Err(AnalysisError::UnexpectedCodeBlockContent { offset: 0 })
+        ));
+    }
+
+    #[test]
+    fn rust_is_generated_even_after_other_languages() {
+        let source = "```toml\nserde = \"1\"\n```\n\n```rust\nfn main() {}\n```\n";
+        assert_eq!(
+            detect_generated_language(source),
+            Some(GeneratedLanguage::Rust)
+        );
+    }
+
+    #[test]
+    fn most_used_language_is_generated_without_rust() {
+        let source = "```toml\n```\n\n```css\n```\n\n```css\n```\n";
+        assert_eq!(
+            detect_generated_language(source),
+            Some(GeneratedLanguage::Css)
+        );
+    }
+
+    #[test]
+    fn heading_without_module_closes_module() {
+        let source = "\
+{mod=parser}
+## Parser
+
+```rust
+fn parse() {}
+```
+
+## Output
+
+```rust
+fn write() {}
+```
+";
+        let options = TangleOptions {
+            modules: true,
+            ..TangleOptions::default()
+        };
+        let generated = tangle(source, &options).unwrap();
+        let close = generated.find("\n}\n").unwrap();
+        assert!(generated.find("fn parse()").unwrap() < close);
+        assert!(generated.find("fn write()").unwrap() > close);
+    }
+
+    #[test]
+    fn example_defining_main_calls_it() {
+        let source = "\
+## Greeting
+
+```rust
+fn main() {
+    println!(\"hello\");
+}
+```
+
+{.output}
+```
+hello
+```
+";
+        let generated = tangle(source, &TangleOptions::default()).unwrap();
+        let body_start = generated.find("fn greeting()").unwrap();
+        let body = &generated[body_start..generated.find("check_output(&output").unwrap()];
+        assert!(body.contains("\n            main();\n        }) else {"));
+    }
+
+    #[test]
+    fn test_block_defining_main_calls_it() {
+        let source = "\
+## Parsing
+
+{.test}
+```rust
+fn main() {
+    assert_eq!(1 + 1, 2);
+}
+```
+";
+        let generated = tangle(source, &TangleOptions::default()).unwrap();
+        assert!(generated.contains("        }\n        main();\n    }\n"));
+    }
+
+    #[test]
+    fn test_function_in_test_block_is_rejected() {
+        let source = "\
+## Parsing
+
+{.test}
+```rust
+#[test]
+fn parses() {}
+```
+";
+        let result = tangle(source, &TangleOptions::default());
+        assert!(matches!(
+            result,
+            Err(ConversionError::NestedTestFunction { line: Some(5) })
+        ));
+    }
+
+    #[test]
+    fn code_blocks_in_rustdoc_prose_are_text() {
+        let source = "\
+# Format
+
+The input looks like this:
+
+```
+key = value
+```
+
+``` json
+{}
+```
+
+```rust
+fn parse() {}
+```
+";
+        let options = TangleOptions {
+            rustdoc: true,
+            ..TangleOptions::default()
+        };
+        let generated = tangle(source, &options).unwrap();
+        assert!(generated.contains("//! ```text\n//! key = value\n//! ```\n"));
+        assert!(generated.contains("//! ```text\n//! {}\n//! ```\n"));
+    }
+
+    #[test]
+    fn equivalent_file_paths_name_one_target() {
+        let source = "\
+{file=./a.rs}
+```rust
+fn first() {}
+```
+
+{file=a.rs}
+```rust
+fn second() {}
+```
+";
+        let blocks = analyze_source_blocks(source.as_bytes()).unwrap();
+        let targets = convert_source_blocks(
+            blocks,
+            GeneratedLanguage::Rust,
+            "doc.lit.djot",
+            &TangleOptions::default(),
+        )
+        .unwrap();
+        let files: Vec<_> = targets
+            .iter()
+            .map(|target| target.file.as_deref())
+            .collect();
+        assert_eq!(files, [Some("a.rs")]);
+    }
+
+    #[test]
+    fn chunk_used_before_its_definition_is_expanded() {
+        let source = "\
+```rust
+fn main() {
+    <<body>>
+}
+```
+
+{chunk=body}
+```rust
+println!(\"hi\");
+```
+";
+        let generated = tangle(source, &TangleOptions::default()).unwrap();
+        assert!(generated.contains("fn main() {\n    println!(\"hi\");\n}\n"));
+    }
+
+    #[test]
+    fn undefined_chunk_is_rejected() {
+        let source = "```rust\nfn main() {\n    <<setup>>\n}\n```\n";
+        let result = tangle(source, &TangleOptions::default());
+        assert!(matches!(
+            result,
+            Err(ConversionError::UndefinedChunk { name, line: Some(3) }) if name == "setup"
+        ));
+    }
+
+    #[test]
+    fn unused_chunk_is_rejected() {
+        let source = "\
+```rust
+fn main() {}
+```
+
+{chunk=helper}
+```rust
+fn helper() {}
+```
+";
+        let result = tangle(source, &TangleOptions::default());
+        assert!(matches!(
+            result,
+            Err(ConversionError::UnusedChunk { name, line: Some(6) }) if name == "helper"
+        ));
+    }
+
+    #[test]
+    fn cyclic_chunk_reference_is_rejected() {
+        let source = "\
+```rust
+<<a>>
+```
+
+{chunk=a}
+```rust
+<<b>>
+```
+
+{chunk=b}
+```rust
+<<a>>
+```
+";
+        let result = tangle(source, &TangleOptions::default());
+        assert!(matches!(
+            result,
+            Err(ConversionError::CyclicChunkReference { name, line: Some(12) }) if name == "a"
+        ));
+    }
+
+    #[test]
+    fn unterminated_fence_is_rejected() {
+        let source = "# Notes\n\n```rust\nfn main() {}\n";
+        let result = analyze_source_blocks(source.as_bytes());
+        assert!(matches!(
+            result,
+            Err(AnalysisError::InvalidDjotBlock { offset: 9 })
+        ));
+    }
+}
@@ -6,26 +6,548 @@ pub(crate) enum SourceToplevelBlock {
     VerbatimBlock {
         lang: GeneratedLanguage,
         lines: Vec<String>,
+        span: Option<SourceSpan>,
+        chunk: Option<String>,
+        file: Option<String>,
+        first_line: Option<usize>,
+        /// Set for Rust blocks marked as tests, and for examples. Such blocks
+        /// are gathered into a `#[cfg(test)]` module at the end of the file,
+        /// or of their module with `TangleOptions::modules`.
+        test: Option<TestBlock>,
     },
     LiterateBlock {
         lines: Vec<String>,
+        span: Option<SourceSpan>,
     },
-    Postamble,
+    /// A heading that opens or closes a module when tangling with
+    /// `TangleOptions::modules`. A heading with a `mod` attribute opens a
+    /// module of that name, which holds everything up to the next heading of
+    /// the same or a higher level. Headings that neither open nor close a
+    /// module are left in the prose.
+    Heading {
+        level: u16,
+        module: Option<HeadingModule>,
+        line: usize,
+    },
+    Postamble {
+        source_hash: Option<u64>,
+    },
+}
+
+impl SourceToplevelBlock {
+    /// Whether the block defines a named chunk, references one, goes to a
+    /// file of its own or is a test, in which case its code does not appear
+    /// verbatim in the default generated file.
+    pub(crate) fn is_rearranged(&self) -> bool {
+        match self {
+            SourceToplevelBlock::VerbatimBlock {
+                lines,
+                chunk,
+                file,
+                test,
+                ..
+            } => {
+                chunk.is_some()
+                    || file.is_some()
+                    || test.is_some()
+                    || lines
+                        .iter()
+                        .any(|line| parse_chunk_reference(line).is_some())
+            }
+            _ => false,
+        }
+    }
+}
+
+/// How a Rust block is turned into a test function.
+#[derive(Clone)]
+pub(crate) struct TestBlock {
+    pub(crate) name: String,
+    /// What the block is documented to print, for an example, i.e. a block
+    /// followed by an output block.
+    pub(crate) expected_output: Option<ExpectedOutput>,
+}
+
+/// The contents of an output block, and the source line they start on.
+#[derive(Clone)]
+pub(crate) struct ExpectedOutput {
+    pub(crate) lines: Vec<String>,
+    pub(crate) first_line: usize,
+}
+
+/// The module a heading opens, from its `mod` attribute.
+#[derive(Clone)]
+pub(crate) struct HeadingModule {
+    pub(crate) name: String,
+    pub(crate) is_pub: bool,
+}
+
+/// The line opening the module of a heading.
+pub(crate) fn module_start_line(name: &str, is_pub: bool) -> String {
+    if is_pub {
+        format!("pub mod {name} {{")
+    } else {
+        format!("mod {name} {{")
+    }
+}
+
+/// The doc comments that carry prose in Rust output when tangling with
+/// `TangleOptions::rustdoc`: outer ones (`///`) document the item that
+/// follows, inner ones (`//!`) the enclosing file or module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DocComment {
+    Outer,
+    Inner,
+}
+
+impl DocComment {
+    fn start(self) -> &'static str {
+        match self {
+            DocComment::Outer => "///",
+            DocComment::Inner => "//!",
+        }
+    }
+
+    /// The width of what `comment_line` puts before the text.
+    pub(crate) fn start_width(self) -> usize {
+        self.start().len() + 1
+    }
+
+    /// Turns `text` into a doc comment line, leaving no trailing space after
+    /// the comment start of empty lines.
+    pub(crate) fn comment_line(self, text: &str) -> String {
+        if text.is_empty() {
+            self.start().to_owned()
+        } else {
+            format!("{} {text}", self.start())
+        }
+    }
+
+    /// The inverse of `comment_line` for either kind of doc comment.
+    pub(crate) fn uncomment_line(line: &str) -> Option<&str> {
+        [DocComment::Outer, DocComment::Inner]
+            .into_iter()
+            .find_map(|doc_comment| {
+                let rest = line.strip_prefix(doc_comment.start())?;
+                if rest.is_empty() {
+                    Some(rest)
+                } else {
+                    rest.strip_prefix(' ')
+                }
+            })
+    }
+}
+
+/// Parses a line consisting of a single chunk reference such as
+/// `    <<parse arguments>>`, returning its indentation and the chunk name.
+pub(crate) fn parse_chunk_reference(line: &str) -> Option<(&str, &str)> {
+    let content = line.trim_start();
+    let indent = &line[..line.len() - content.len()];
+    let name = content
+        .trim_end()
+        .strip_prefix("<<")?
+        .strip_suffix(">>")?
+        .trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((indent, name))
+}
+
+/// The location of a block within the Djot source, as byte offsets and as
+/// 1-based line numbers. Both ranges are half-open.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SourceSpan {
+    pub(crate) bytes: Range<usize>,
+    pub(crate) lines: Range<usize>,
+}
+
+impl SourceSpan {
+    pub(crate) fn from_byte_range(source: &str, bytes: Range<usize>) -> Self {
+        let start_line = source[..bytes.start].matches('\n').count() + 1;
+        let line_count = source[bytes.clone()]
+            .trim_end_matches('\n')
+            .matches('\n')
+            .count()
+            + 1;
+        SourceSpan {
+            bytes,
+            lines: start_line..start_line + line_count,
+        }
+    }
+}
+
+/// The line groups of one generated file. `file` is the path given by a
+/// `file` attribute, relative to the source, or `None` for the default output.
+///
+/// `line_origins` runs parallel to `line_groups`, giving the origin of every
+/// line of each code line group.
+pub(crate) struct GeneratedTarget {
+    pub(crate) file: Option<String>,
+    pub(crate) lang: GeneratedLanguage,
+    pub(crate) line_groups: Vec<GeneratedLineGroup>,
+    pub(crate) line_origins: Vec<Vec<Option<LineOrigin>>>,
+}
+
+impl GeneratedTarget {
+    pub(crate) fn new(file: Option<String>, lang: GeneratedLanguage) -> Self {
+        GeneratedTarget {
+            file,
+            lang,
+            line_groups: vec![GeneratedLineGroup::Preamble],
+            line_origins: vec![vec![]],
+        }
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        line_group: GeneratedLineGroup,
+        origins: Vec<Option<LineOrigin>>,
+    ) {
+        self.line_groups.push(line_group);
+        self.line_origins.push(origins);
+    }
+}
+
+/// The source line a generated line was tangled from, and the source column
+/// that the generated column `generated_column` corresponds to. Everything
+/// is 1-based.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct LineOrigin {
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    pub(crate) generated_column: usize,
 }
 
 #[derive(Clone)]
 pub(crate) enum GeneratedLineGroup {
     Preamble,
     CodeLineGroup(Vec<String>),
-    Postamble { source_lang: SourceLanguage },
+    MarkedCodeLineGroup {
+        marker: BlockMarker,
+        lines: Vec<String>,
+    },
+    Postamble {
+        source_lang: SourceLanguage,
+        signature: GeneratedSignature,
+    },
 }
 
-#[derive(Clone, Copy)]
+/// Identifies the source block a marked group of generated lines came from.
+///
+/// Markers are written as a pair of comment lines around the code, e.g.
+/// `// [LITWEB block=3 bytes=120..245 lines=10..18 hash=0123456789abcdef]`
+/// and `// [LITWEB end=3]`, in the comment syntax of the generated language.
+/// The hash covers the code lines of the block as they were when the file
+/// was tangled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BlockMarker {
+    pub(crate) index: usize,
+    pub(crate) span: SourceSpan,
+    pub(crate) hash: u64,
+}
+
+impl BlockMarker {
+    pub(crate) fn start_line(&self, lang: GeneratedLanguage) -> String {
+        let BlockMarker { index, span, hash } = self;
+        lang.comment_line(&format!(
+            "[LITWEB block={index} bytes={}..{} lines={}..{} hash={hash:016x}]",
+            span.bytes.start, span.bytes.end, span.lines.start, span.lines.end
+        ))
+    }
+
+    pub(crate) fn end_line(&self, lang: GeneratedLanguage) -> String {
+        lang.comment_line(&format!("[LITWEB end={}]", self.index))
+    }
+
+    pub(crate) fn parse_start_line(line: &str, lang: GeneratedLanguage) -> Option<Self> {
+        let text = lang.uncomment_line(line.trim())?;
+        let fields = text.strip_prefix("[LITWEB ")?.strip_suffix(']')?;
+        let mut index = None;
+        let mut bytes = None;
+        let mut lines = None;
+        let mut hash = None;
+        for field in fields.split(' ') {
+            let (key, value) = field.split_once('=')?;
+            match key {
+                "block" => index = Some(value.parse().ok()?),
+                "bytes" => bytes = Some(parse_range(value)?),
+                "lines" => lines = Some(parse_range(value)?),
+                "hash" => hash = Some(u64::from_str_radix(value, 16).ok()?),
+                _ => return None,
+            }
+        }
+        Some(BlockMarker {
+            index: index?,
+            span: SourceSpan {
+                bytes: bytes?,
+                lines: lines?,
+            },
+            hash: hash?,
+        })
+    }
+
+    pub(crate) fn is_end_line(&self, line: &str, lang: GeneratedLanguage) -> bool {
+        line.trim() == self.end_line(lang)
+    }
+}
+
+fn parse_range(value: &str) -> Option<Range<usize>> {
+    let (start, end) = value.split_once("..")?;
+    Some(start.parse().ok()?..end.parse().ok()?)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum GeneratedLanguage {
     Rust,
+    Toml,
+    Sql,
+    Shell,
+    Python,
+    Css,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum CommentSyntax {
+    Line(&'static str),
+    Block(&'static str, &'static str),
 }
 
-pub(crate) const GENERATED_SIGNATURE: &'static str = "// [LITWEB djot->rust]";
+/// What litweb needs to know about a generated language: the code block
+/// tags that select it, the extension of the files it is tangled to, and
+/// how to write the comments that carry prose, block markers and the
+/// signature.
+pub(crate) struct LanguageInfo {
+    pub(crate) lang: GeneratedLanguage,
+    pub(crate) name: &'static str,
+    pub(crate) tags: &'static [&'static str],
+    pub(crate) extension: &'static str,
+    pub(crate) comment: CommentSyntax,
+}
+
+pub(crate) const LANGUAGES: &[LanguageInfo] = &[
+    LanguageInfo {
+        lang: GeneratedLanguage::Rust,
+        name: "rust",
+        tags: &["rust", "rs"],
+        extension: "rs",
+        comment: CommentSyntax::Line("//"),
+    },
+    LanguageInfo {
+        lang: GeneratedLanguage::Toml,
+        name: "toml",
+        tags: &["toml"],
+        extension: "toml",
+        comment: CommentSyntax::Line("#"),
+    },
+    LanguageInfo {
+        lang: GeneratedLanguage::Sql,
+        name: "sql",
+        tags: &["sql"],
+        extension: "sql",
+        comment: CommentSyntax::Line("--"),
+    },
+    LanguageInfo {
+        lang: GeneratedLanguage::Shell,
+        name: "shell",
+        tags: &["sh", "bash", "shell"],
+        extension: "sh",
+        comment: CommentSyntax::Line("#"),
+    },
+    LanguageInfo {
+        lang: GeneratedLanguage::Python,
+        name: "python",
+        tags: &["python", "py"],
+        extension: "py",
+        comment: CommentSyntax::Line("#"),
+    },
+    LanguageInfo {
+        lang: GeneratedLanguage::Css,
+        name: "css",
+        tags: &["css"],
+        extension: "css",
+        comment: CommentSyntax::Block("/*", "*/"),
+    },
+];
+
+impl GeneratedLanguage {
+    pub(crate) fn info(self) -> &'static LanguageInfo {
+        LANGUAGES.iter().find(|info| info.lang == self).unwrap()
+    }
+
+    pub(crate) fn from_code_block_tag(tag: &str) -> Option<Self> {
+        LANGUAGES
+            .iter()
+            .find(|info| info.tags.contains(&tag))
+            .map(|info| info.lang)
+    }
+
+    pub(crate) fn from_extension(extension: &str) -> Option<Self> {
+        LANGUAGES
+            .iter()
+            .find(|info| info.extension == extension)
+            .map(|info| info.lang)
+    }
+
+    /// The tag written on code blocks of this language when untangling.
+    pub(crate) fn code_block_tag(self) -> &'static str {
+        self.info().tags[0]
+    }
+
+    /// The width of what `comment_line` puts before the text.
+    pub(crate) fn comment_start_width(self) -> usize {
+        match self.info().comment {
+            CommentSyntax::Line(start) | CommentSyntax::Block(start, _) => {
+                start.chars().count() + 1
+            }
+        }
+    }
+
+    /// Turns `text` into a single comment line, e.g. `// text`. In block
+    /// comments, a `*` followed by `/` or `\` gets a `\` inserted after it,
+    /// so that the text cannot end the comment early.
+    pub(crate) fn comment_line(self, text: &str) -> String {
+        match self.info().comment {
+            CommentSyntax::Line(start) => format!("{start} {text}"),
+            CommentSyntax::Block(start, end) => {
+                format!("{start} {} {end}", escape_block_comment_text(text))
+            }
+        }
+    }
+
+    /// The inverse of `comment_line`, or `None` if `line` is not a comment
+    /// of that form.
+    pub(crate) fn uncomment_line(self, line: &str) -> Option<Cow<'_, str>> {
+        match self.info().comment {
+            CommentSyntax::Line(start) => line
+                .strip_prefix(start)?
+                .strip_prefix(' ')
+                .map(Cow::Borrowed),
+            CommentSyntax::Block(start, end) => line
+                .strip_prefix(start)?
+                .strip_prefix(' ')?
+                .strip_suffix(end)?
+                .strip_suffix(' ')
+                .map(unescape_block_comment_text),
+        }
+    }
+}
+
+fn escape_block_comment_text(text: &str) -> Cow<'_, str> {
+    if !text.contains("*/") && !text.contains("*\\") {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len() + 1);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        escaped.push(c);
+        if c == '*' && matches!(chars.peek(), Some('/' | '\\')) {
+            escaped.push('\\');
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn unescape_block_comment_text(text: &str) -> Cow<'_, str> {
+    if !text.contains("*\\") {
+        return Cow::Borrowed(text);
+    }
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        unescaped.push(c);
+        if c == '*' && chars.peek() == Some(&'\\') {
+            chars.next();
+            if let Some(escaped) = chars.next() {
+                unescaped.push(escaped);
+            }
+        }
+    }
+    Cow::Owned(unescaped)
+}
+
+/// The trailing line of a generated file.
+///
+/// Besides identifying the file as generated, it records the hash of the
+/// source it was tangled from and of the generated text above it, e.g.
+/// `// [LITWEB djot->rust src=0123456789abcdef gen=fedcba9876543210]`, so that
+/// edits made on either side since the last tangle can be detected. Files
+/// written before the hashes were introduced carry a bare
+/// `// [LITWEB djot->rust]`, which parses with both hashes absent. Rust
+/// written in rustdoc mode is flagged with a trailing `rustdoc`, since only
+/// then are its doc comments prose.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct GeneratedSignature {
+    pub(crate) lang: GeneratedLanguage,
+    pub(crate) source_hash: Option<u64>,
+    pub(crate) generated_hash: Option<u64>,
+    pub(crate) rustdoc: bool,
+}
+
+impl GeneratedSignature {
+    pub(crate) fn line(&self) -> String {
+        let mut text = format!("[LITWEB djot->{}", self.lang.info().name);
+        if let Some(source_hash) = self.source_hash {
+            text.push_str(&format!(" src={source_hash:016x}"));
+        }
+        if let Some(generated_hash) = self.generated_hash {
+            text.push_str(&format!(" gen={generated_hash:016x}"));
+        }
+        if self.rustdoc {
+            text.push_str(" rustdoc");
+        }
+        text.push(']');
+        self.lang.comment_line(&text)
+    }
+
+    /// The name of the source language a signature line gives, e.g. `md` for
+    /// `// [LITWEB md->rust]`, whether or not litweb supports it.
+    pub(crate) fn parse_source_name(line: &str, lang: GeneratedLanguage) -> Option<String> {
+        let text = lang.uncomment_line(line.trim())?;
+        let (source_name, _) = text.strip_prefix("[LITWEB ")?.split_once("->")?;
+        Some(source_name.to_owned())
+    }
+
+    pub(crate) fn parse_line(line: &str, lang: GeneratedLanguage) -> Option<Self> {
+        let text = lang.uncomment_line(line.trim())?;
+        let fields = text
+            .strip_prefix("[LITWEB djot->")?
+            .strip_prefix(lang.info().name)?
+            .strip_suffix(']')?;
+        let mut result = GeneratedSignature {
+            lang,
+            source_hash: None,
+            generated_hash: None,
+            rustdoc: false,
+        };
+        for field in fields.split_whitespace() {
+            if field == "rustdoc" {
+                result.rustdoc = true;
+                continue;
+            }
+            let (key, value) = field.split_once('=')?;
+            let hash = u64::from_str_radix(value, 16).ok()?;
+            match key {
+                "src" => result.source_hash = Some(hash),
+                "gen" => result.generated_hash = Some(hash),
+                _ => return None,
+            }
+        }
+        Some(result)
+    }
+
+    /// Splits a generated file into the text above the signature and the
+    /// signature itself. Returns `None` if the file does not end with one.
+    pub(crate) fn split_generated_text(
+        text: &str,
+        lang: GeneratedLanguage,
+    ) -> Option<(&str, Self)> {
+        let trimmed = text.trim_end();
+        let line_start = trimmed.rfind('\n').map_or(0, |pos| pos + 1);
+        let signature = Self::parse_line(&trimmed[line_start..], lang)?;
+        Some((&text[..line_start], signature))
+    }
+}
 
 #[derive(Clone, Copy)]
 pub(crate) enum SourceLanguage {
@@ -36,6 +558,8 @@ pub(crate) enum SourceLanguage {
 pub enum GenerationError {
     #[error(transparent)]
     Io(#[from] io::Error),
+    #[error("Only Djot sources are supported")]
+    UnsupportedSourceLanguage,
 }
 
 #[derive(Error, Debug)]
@@ -44,8 +568,42 @@ pub enum ConversionError {
     NoValidPreamble,
     #[error("No valid postamble is found")]
     NoValidPostamble,
+    #[error("Only Djot sources are supported")]
+    UnsupportedSourceLanguage,
     #[error("Unexpected generated line group is met")]
     UnexpectedGeneratedLineGroup,
+    #[error("Marked block {index} is not a code block of the source")]
+    UnknownMarkedBlock { index: usize },
+    #[error("Marked block {index} has changed in the source since it was tangled")]
+    MarkedBlockChanged { index: usize, offset: usize },
+    #[error("Generated file has {found} prose blocks but the source has {expected}")]
+    ProseBlockCountMismatch { expected: usize, found: usize },
+    #[error("Chunk `{name}` is referenced but never defined")]
+    UndefinedChunk { name: String, line: Option<usize> },
+    #[error("Chunk `{name}` is defined but never referenced")]
+    UnusedChunk { name: String, line: Option<usize> },
+    #[error("Chunk `{name}` references itself, directly or through other chunks")]
+    CyclicChunkReference { name: String, line: Option<usize> },
+    #[error("Output file `{file}` is not inside the directory of the source")]
+    OutputPathOutsideSourceDir { file: String, line: Option<usize> },
+    #[error("Test block defines a test function of its own, which would never run")]
+    NestedTestFunction { line: Option<usize> },
+    #[error("Source uses named chunks, several output files or test blocks, so generated code cannot be merged back into it")]
+    RearrangedSourceCannotBeMerged,
+    #[error("Generated file has no prose to line it up with the source, so it can only be merged back with block markers")]
+    OutputWithoutProseCannotBeMerged,
+    #[error("Code wrapped in modules can only be merged back with block markers")]
+    ModulesCannotBeMerged,
+    #[error("Prose cannot be converted to rustdoc: {0}")]
+    RustdocConversion(#[from] WeaveConversionError),
+    /// Only reported with the `validate` feature, but always defined so that
+    /// enabling the feature does not change the enum.
+    #[error("Tangled Rust does not parse: {message}")]
+    InvalidRustSyntax {
+        message: String,
+        line: Option<usize>,
+        column: Option<usize>,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -53,10 +611,301 @@ pub enum AnalysisError {
     #[error(transparent)]
     Io(#[from] io::Error),
     #[error("No valid postamble is found")]
-    NoValidPostamble,
+    NoValidPostamble { line: usize },
     #[error("Invalid Djot block event occurrred.")]
-    InvalidDjotBlock,
+    InvalidDjotBlock { offset: usize },
+    #[error("Code block contains something other than plain text")]
+    UnexpectedCodeBlockContent { offset: usize },
+    #[error("Only Djot sources are supported")]
+    UnsupportedSourceLanguage,
+    #[error("Block marker for block {index} is not terminated")]
+    UnterminatedBlockMarker { index: usize, line: usize },
+    #[error("Output block does not directly follow a Rust code block")]
+    UnpairedOutputBlock { offset: usize },
+}
+
+/// Where an error was found. Lines are 1-based.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorPosition {
+    /// A byte offset into the source document.
+    SourceOffset(usize),
+    SourceLine(usize),
+    /// A 1-based line and column of the source document.
+    SourceColumn(usize, usize),
+    GeneratedLine(usize),
+}
+
+impl ConversionError {
+    pub(crate) fn position(&self) -> Option<ErrorPosition> {
+        match *self {
+            ConversionError::MarkedBlockChanged { offset, .. } => {
+                Some(ErrorPosition::SourceOffset(offset))
+            }
+            ConversionError::UndefinedChunk { line, .. }
+            | ConversionError::UnusedChunk { line, .. }
+            | ConversionError::CyclicChunkReference { line, .. }
+            | ConversionError::OutputPathOutsideSourceDir { line, .. }
+            | ConversionError::NestedTestFunction { line } => line.map(ErrorPosition::SourceLine),
+            ConversionError::InvalidRustSyntax { line, column, .. } => match (line, column) {
+                (Some(line), Some(column)) => Some(ErrorPosition::SourceColumn(line, column)),
+                (line, _) => line.map(ErrorPosition::SourceLine),
+            },
+            _ => None,
+        }
+    }
 }
 
+impl AnalysisError {
+    pub(crate) fn position(&self) -> Option<ErrorPosition> {
+        match *self {
+            AnalysisError::Io(_) | AnalysisError::UnsupportedSourceLanguage => None,
+            AnalysisError::InvalidDjotBlock { offset }
+            | AnalysisError::UnexpectedCodeBlockContent { offset }
+            | AnalysisError::UnpairedOutputBlock { offset } => {
+                Some(ErrorPosition::SourceOffset(offset))
+            }
+            AnalysisError::NoValidPostamble { line }
+            | AnalysisError::UnterminatedBlockMarker { line, .. } => {
+                Some(ErrorPosition::GeneratedLine(line))
+            }
+        }
+    }
+}
+
+use crate::weave_and_unweave::ConversionError as WeaveConversionError;
+use std::borrow::Cow;
 use std::io;
+use std::ops::Range;
 use thiserror::Error;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tangle;
+    use crate::untangle;
+    use crate::utils;
+    use crate::TangleOptions;
+
+    fn tangle(source: &str) -> String {
+        tangle_with(source, &TangleOptions::default())
+    }
+
+    fn tangle_with(source: &str, options: &TangleOptions) -> String {
+        let blocks = tangle::analyze_source_blocks(source.as_bytes()).unwrap();
+        let targets =
+            tangle::convert_source_blocks(blocks, GeneratedLanguage::Rust, "doc.lit.djot", options)
+                .unwrap();
+        let mut generated = vec![];
+        tangle::generate_output(&mut generated, targets.into_iter().next().unwrap()).unwrap();
+        String::from_utf8(generated).unwrap()
+    }
+
+    fn untangle(generated: &str) -> String {
+        let groups =
+            untangle::analyze_line_groups(generated.as_bytes(), GeneratedLanguage::Rust).unwrap();
+        let blocks = untangle::convert_line_groups(groups, GeneratedLanguage::Rust).unwrap();
+        let mut source = vec![];
+        untangle::generate_output(&mut source, blocks).unwrap();
+        String::from_utf8(source).unwrap()
+    }
+
+    /// Merges a generated file back into the source it was tangled from.
+    /// Merges a generated file with block markers back into the source it
+    /// was tangled from.
+    fn merge_marked(generated: &str, source: &str) -> Result<String, ConversionError> {
+        let groups =
+            untangle::analyze_line_groups(generated.as_bytes(), GeneratedLanguage::Rust).unwrap();
+        assert!(untangle::has_block_markers(&groups));
+        let blocks = tangle::analyze_source_blocks(source.as_bytes()).unwrap();
+        let edits = untangle::convert_marked_line_groups(groups, source, blocks)?;
+        let mut merged = vec![];
+        untangle::generate_edited_output(&mut merged, source, edits).unwrap();
+        Ok(String::from_utf8(merged).unwrap())
+    }
+
+    /// Merges a generated file back into the source it was tangled from.
+    fn merge(generated: &str, source: &str) -> String {
+        let groups =
+            untangle::analyze_line_groups(generated.as_bytes(), GeneratedLanguage::Rust).unwrap();
+        let blocks = tangle::analyze_source_blocks(source.as_bytes()).unwrap();
+        let edits =
+            untangle::convert_line_groups_to_edits(groups, GeneratedLanguage::Rust, source, blocks)
+                .unwrap();
+        let mut merged = vec![];
+        untangle::generate_edited_output(&mut merged, source, edits).unwrap();
+        String::from_utf8(merged).unwrap()
+    }
+
+    /// The generated text without the signature, whose source hash differs
+    /// between equivalent sources.
+    fn body(generated: &str) -> &str {
+        let trimmed = generated.trim_end();
+        &trimmed[..trimmed.rfind('\n').unwrap()]
+    }
+
+    fn assert_round_trips(source: &str) {
+        let generated = tangle(source);
+        assert_eq!(body(&tangle(&untangle(&generated))), body(&generated));
+        assert_eq!(merge(&generated, source), source);
+    }
+
+    #[test]
+    fn prose_and_code_round_trip() {
+        assert_round_trips(
+            "\
+# Greeting
+
+Prints a greeting.
+
+```rust
+fn main() {
+    println!(\"hello\");
+
+    println!(\"bye\");
+}
+```
+
+That is all.
+",
+        );
+    }
+
+    #[test]
+    fn nested_code_blocks_round_trip() {
+        assert_round_trips(
+            "\
+# Parts
+
+::: note
+The parser:
+
+```rust
+fn parse() {}
+```
+:::
+
+```rust
+fn main() {}
+```
+",
+        );
+    }
+
+    #[test]
+    fn comment_only_code_round_trips() {
+        assert_round_trips(
+            "\
+# Plan
+
+The entry point comes later.
+
+```rust
+// TODO: write main
+```
+
+```rust
+fn helper() {}
+```
+",
+        );
+    }
+
+    #[test]
+    fn code_next_to_comment_only_code_merges_edits() {
+        let source = "\
+The entry point comes later.
+
+```rust
+// TODO: write main
+```
+
+Until then, a helper.
+
+```rust
+fn helper() {}
+```
+";
+        let generated = tangle(source).replace("fn helper() {}", "fn helper() -> u8 { 0 }");
+        assert_eq!(
+            merge(&generated, source),
+            source.replace("fn helper() {}", "fn helper() -> u8 { 0 }")
+        );
+    }
+
+    const MARKED_SOURCE: &str = "\
+# Greeting
+
+```rust
+fn greet() {
+    println!(\"hello\");
+}
+```
+
+The entry point *calls* it.
+
+```rust
+fn main() {
+    greet();
+}
+```
+";
+
+    #[test]
+    fn marked_block_edit_merges_into_its_block() {
+        let options = TangleOptions {
+            block_markers: true,
+            ..TangleOptions::default()
+        };
+        let generated = tangle_with(MARKED_SOURCE, &options)
+            .replace("\"hello\"", "\"hello, world\"")
+            .replace("The entry point *calls* it.", "Rewritten prose.");
+        assert_eq!(
+            merge_marked(&generated, MARKED_SOURCE).unwrap(),
+            MARKED_SOURCE.replace("\"hello\"", "\"hello, world\"")
+        );
+    }
+
+    #[test]
+    fn marked_block_changed_in_source_is_rejected() {
+        let options = TangleOptions {
+            block_markers: true,
+            ..TangleOptions::default()
+        };
+        let generated =
+            tangle_with(MARKED_SOURCE, &options).replace("greet();", "greet();\n    greet();");
+        let source = MARKED_SOURCE.replace("greet();", "greet(); // edited");
+        let offset = source.find("```rust\nfn main").unwrap();
+        assert!(matches!(
+            merge_marked(&generated, &source),
+            Err(ConversionError::MarkedBlockChanged { offset: found, .. }) if found == offset
+        ));
+    }
+
+    #[test]
+    fn block_markers_locate_their_source_blocks() {
+        let options = TangleOptions {
+            block_markers: true,
+            ..TangleOptions::default()
+        };
+        let generated = tangle_with(MARKED_SOURCE, &options);
+        let markers = generated
+            .lines()
+            .filter_map(|line| BlockMarker::parse_start_line(line, GeneratedLanguage::Rust))
+            .collect::<Vec<_>>();
+        assert_eq!(markers.len(), 2);
+        for marker in &markers {
+            assert!(generated.contains(&marker.start_line(GeneratedLanguage::Rust)));
+            assert!(generated.contains(&marker.end_line(GeneratedLanguage::Rust)));
+        }
+        let greet = &markers[0];
+        assert_eq!(greet.span.lines, 3..8);
+        assert!(MARKED_SOURCE[greet.span.bytes.clone()].starts_with("```rust\nfn greet()"));
+        assert_eq!(
+            greet.hash,
+            utils::content_hash("fn greet() {\n    println!(\"hello\");\n}")
+        );
+        let main = &markers[1];
+        assert!(main.index > greet.index);
+        assert!(MARKED_SOURCE[main.span.bytes.clone()].starts_with("```rust\nfn main()"));
+    }
+}
@@ -6,13 +6,14 @@ pub(crate) fn generate_output(
     for source_line in source {
         match source_line {
             SourceToplevelBlock::Preamble { lang } => {
-                assert!(matches!(lang, SourceLanguage::Djot));
+                if !matches!(lang, SourceLanguage::Djot) {
+                    return Err(GenerationError::UnsupportedSourceLanguage);
+                }
             }
-            SourceToplevelBlock::VerbatimBlock { lang, lines } => {
+            SourceToplevelBlock::VerbatimBlock { lang, lines, .. } => {
                 if !mem::replace(&mut first_block, false) {
                     writeln!(writer)?;
                 }
-                assert!(matches!(lang, GeneratedLanguage::Rust));
                 let (fence_start, fence_end) = calc_djot_fences(lang, &lines);
                 writeln!(writer, "{}", fence_start)?;
                 for line in lines {
@@ -20,7 +21,7 @@ pub(crate) fn generate_output(
                 }
                 writeln!(writer, "{}", fence_end)?;
             }
-            SourceToplevelBlock::LiterateBlock { lines } => {
+            SourceToplevelBlock::LiterateBlock { lines, .. } => {
                 if !mem::replace(&mut first_block, false) {
                     writeln!(writer)?;
                 }
@@ -28,12 +29,35 @@ pub(crate) fn generate_output(
                     writeln!(writer, "{}", line)?;
                 }
             }
-            SourceToplevelBlock::Postamble => {}
+            SourceToplevelBlock::Heading { .. } | SourceToplevelBlock::Postamble { .. } => {}
         }
     }
     Ok(())
 }
 
+/// Writes the source with every edit applied. Edits must not overlap.
+pub(crate) fn generate_edited_output(
+    mut writer: impl io::Write,
+    source: &str,
+    mut edits: Vec<SourceEdit>,
+) -> Result<(), GenerationError> {
+    edits.sort_by_key(|edit| edit.range.start);
+    let mut position = 0;
+    for edit in edits {
+        writer.write_all(&source.as_bytes()[position..edit.range.start])?;
+        writer.write_all(edit.replacement.as_bytes())?;
+        position = edit.range.end;
+    }
+    writer.write_all(&source.as_bytes()[position..])?;
+    Ok(())
+}
+
+/// A replacement of a byte range of the existing Djot source.
+pub(crate) struct SourceEdit {
+    pub(crate) range: Range<usize>,
+    pub(crate) replacement: String,
+}
+
 fn calc_djot_fences(lang: GeneratedLanguage, lines: &[String]) -> (String, String) {
     let mut fence_len = 3;
     for line in lines.iter() {
@@ -46,74 +70,543 @@ fn calc_djot_fences(lang: GeneratedLanguage, lines: &[String]) -> (String, Strin
         }
     }
     let fence_end = std::iter::repeat('`').take(fence_len).collect();
-    let fence_start = format!(
-        "{fence_end} {}",
-        match lang {
-            GeneratedLanguage::Rust => "rust",
-        }
-    );
+    let fence_start = format!("{fence_end} {}", lang.code_block_tag());
     (fence_start, fence_end)
 }
 
+/// Converts the line groups of a generated file back into source blocks.
+///
+/// Groups made up solely of comment lines are the prose that
+/// `tangle::convert_source_blocks` emitted for literate blocks, so they are
+/// turned back into literate blocks. Adjacent code groups are joined into a
+/// single verbatim block, with the blank lines that separated them kept as
+/// part of the code. Tangling the result reproduces the generated file
+/// exactly.
 pub(crate) fn convert_line_groups(
     generated: Vec<GeneratedLineGroup>,
     generated_lang: GeneratedLanguage,
+) -> Result<Vec<SourceToplevelBlock>, ConversionError> {
+    convert_line_groups_with_code(generated, generated_lang, &HashSet::new())
+}
+
+/// Converts the line groups of a generated file back into source blocks
+/// like `convert_line_groups`, except that groups in `code_groups` are code
+/// even if they are made up solely of comment lines.
+fn convert_line_groups_with_code(
+    generated: Vec<GeneratedLineGroup>,
+    generated_lang: GeneratedLanguage,
+    code_groups: &HashSet<&[String]>,
 ) -> Result<Vec<SourceToplevelBlock>, ConversionError> {
     let mut generated: VecDeque<_> = generated.into();
     let Some(GeneratedLineGroup::Preamble) = generated.pop_front() else {
         return Err(ConversionError::NoValidPreamble);
     };
-    let Some(GeneratedLineGroup::Postamble { source_lang }) = generated.pop_back() else {
+    let Some(GeneratedLineGroup::Postamble {
+        source_lang,
+        signature,
+    }) = generated.pop_back()
+    else {
         return Err(ConversionError::NoValidPostamble);
     };
-    assert!(matches!(source_lang, SourceLanguage::Djot));
+    if !matches!(source_lang, SourceLanguage::Djot) {
+        return Err(ConversionError::UnsupportedSourceLanguage);
+    }
     let mut result_deque = VecDeque::new();
     result_deque.push_back(SourceToplevelBlock::Preamble {
         lang: SourceLanguage::Djot,
     });
     for line in generated {
         match line {
+            GeneratedLineGroup::CodeLineGroup(lines) if code_groups.contains(&lines[..]) => {
+                push_code_lines(&mut result_deque, generated_lang, lines);
+            }
             GeneratedLineGroup::CodeLineGroup(lines) => {
-                result_deque.push_back(SourceToplevelBlock::VerbatimBlock {
-                    lang: generated_lang,
-                    lines,
-                })
+                if let Some(literate_lines) =
+                    strip_literate_prefix(&lines, generated_lang, signature.rustdoc)
+                {
+                    result_deque.push_back(SourceToplevelBlock::LiterateBlock {
+                        lines: literate_lines,
+                        span: None,
+                    });
+                    continue;
+                }
+                push_code_lines(&mut result_deque, generated_lang, lines);
+            }
+            GeneratedLineGroup::MarkedCodeLineGroup { lines, .. } => {
+                push_code_lines(&mut result_deque, generated_lang, lines);
             }
             GeneratedLineGroup::Preamble | GeneratedLineGroup::Postamble { .. } => {
                 return Err(ConversionError::UnexpectedGeneratedLineGroup);
             }
         }
     }
-    result_deque.push_back(SourceToplevelBlock::Postamble);
+    result_deque.push_back(SourceToplevelBlock::Postamble { source_hash: None });
     Ok(result_deque.into())
 }
 
+fn push_code_lines(
+    result_deque: &mut VecDeque<SourceToplevelBlock>,
+    generated_lang: GeneratedLanguage,
+    lines: Vec<String>,
+) {
+    if let Some(SourceToplevelBlock::VerbatimBlock {
+        lines: previous_lines,
+        ..
+    }) = result_deque.back_mut()
+    {
+        previous_lines.push(String::new());
+        previous_lines.extend(lines);
+        return;
+    }
+    result_deque.push_back(SourceToplevelBlock::VerbatimBlock {
+        lang: generated_lang,
+        lines,
+        span: None,
+        chunk: None,
+        file: None,
+        first_line: None,
+        test: None,
+    })
+}
+
+/// Strips the comment syntax from a group of prose lines, which in Rust
+/// written in rustdoc mode may also be doc comments.
+fn strip_literate_prefix(
+    lines: &[String],
+    lang: GeneratedLanguage,
+    rustdoc: bool,
+) -> Option<Vec<String>> {
+    if lines.is_empty() {
+        return None;
+    }
+    lines
+        .iter()
+        .map(|line| {
+            lang.uncomment_line(line)
+                .or_else(|| match lang {
+                    GeneratedLanguage::Rust if rustdoc => {
+                        DocComment::uncomment_line(line).map(Cow::Borrowed)
+                    }
+                    _ => None,
+                })
+                .map(Cow::into_owned)
+        })
+        .collect()
+}
+
+pub(crate) fn has_block_markers(generated: &[GeneratedLineGroup]) -> bool {
+    generated
+        .iter()
+        .any(|group| matches!(group, GeneratedLineGroup::MarkedCodeLineGroup { .. }))
+}
+
+/// Computes the edits that bring the code blocks of the existing source in
+/// line with the marked line groups of a generated file.
+///
+/// Each marker names the index of the source block it was tangled from,
+/// along with a hash of that block's code at the time. Only blocks whose
+/// code differs from the generated lines are edited; prose and unmarked
+/// groups of the generated file are ignored.
+pub(crate) fn convert_marked_line_groups(
+    generated: Vec<GeneratedLineGroup>,
+    source: &str,
+    source_blocks: Vec<SourceToplevelBlock>,
+) -> Result<Vec<SourceEdit>, ConversionError> {
+    if source_blocks.iter().any(SourceToplevelBlock::is_rearranged) {
+        return Err(ConversionError::RearrangedSourceCannotBeMerged);
+    }
+    let mut edits = vec![];
+    for group in generated {
+        let GeneratedLineGroup::MarkedCodeLineGroup { marker, lines } = group else {
+            continue;
+        };
+        let index = marker.index;
+        let Some(SourceToplevelBlock::VerbatimBlock {
+            lang,
+            lines: source_lines,
+            span: Some(span),
+            ..
+        }) = source_blocks.get(index)
+        else {
+            return Err(ConversionError::UnknownMarkedBlock { index });
+        };
+        if utils::content_hash(source_lines.join("\n")) != marker.hash {
+            return Err(ConversionError::MarkedBlockChanged {
+                index,
+                offset: span.bytes.start,
+            });
+        }
+        if *source_lines == lines {
+            continue;
+        }
+        edits.push(replace_code_block(source, span, *lang, &lines));
+    }
+    Ok(edits)
+}
+
+/// Computes the edits that bring the code blocks of the existing source in
+/// line with an unmarked generated file, leaving all prose untouched.
+///
+/// Prose blocks serve as anchors: the code between two consecutive prose
+/// blocks of the generated file is compared with the code blocks between
+/// the corresponding prose blocks of the source, and only runs of code that
+/// differ are rewritten. A run of several adjacent source code blocks that
+/// changed is replaced by a single block.
+pub(crate) fn convert_line_groups_to_edits(
+    generated: Vec<GeneratedLineGroup>,
+    generated_lang: GeneratedLanguage,
+    source: &str,
+    source_blocks: Vec<SourceToplevelBlock>,
+) -> Result<Vec<SourceEdit>, ConversionError> {
+    // Code blocks in other languages are tangled to other files.
+    let source_blocks = source_blocks
+        .into_iter()
+        .filter(|block| {
+            !matches!(block, SourceToplevelBlock::VerbatimBlock { lang, .. } if *lang != generated_lang)
+        })
+        .collect::<Vec<_>>();
+    if source_blocks.iter().any(SourceToplevelBlock::is_rearranged) {
+        return Err(ConversionError::RearrangedSourceCannotBeMerged);
+    }
+    // Without markers there is no telling the lines opening and closing
+    // modules apart from the code inside them.
+    let module_lines = source_blocks
+        .iter()
+        .filter_map(|block| match block {
+            SourceToplevelBlock::Heading {
+                module: Some(module),
+                ..
+            } => Some(module_start_line(&module.name, module.is_pub)),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let has_modules = generated.iter().any(|group| {
+        matches!(group, GeneratedLineGroup::CodeLineGroup(lines)
+            if lines.len() == 1 && module_lines.contains(&lines[0]))
+    });
+    if has_modules {
+        return Err(ConversionError::ModulesCannotBeMerged);
+    }
+    // Code made up solely of comments looks just like prose in the
+    // generated file, so the groups the source's code blocks were tangled
+    // to are taken for code as long as they are unchanged.
+    let code_groups = source_blocks
+        .iter()
+        .filter_map(|block| match block {
+            SourceToplevelBlock::VerbatimBlock { lines, .. } => Some(lines),
+            _ => None,
+        })
+        .flat_map(|lines| lines.split(String::is_empty))
+        .filter(|group| !group.is_empty())
+        .collect::<HashSet<_>>();
+    let generated_blocks = convert_line_groups_with_code(generated, generated_lang, &code_groups)?;
+    let generated_segments = split_code_segments(&generated_blocks);
+    let source_segments = split_code_segments(&source_blocks);
+    // Outputs for languages other than the main one carry no prose, so
+    // there is nothing to line their code up with the source by.
+    if generated_segments.len() == 1 && source_segments.len() > 1 {
+        return Err(ConversionError::OutputWithoutProseCannotBeMerged);
+    }
+    if generated_segments.len() != source_segments.len() {
+        return Err(ConversionError::ProseBlockCountMismatch {
+            expected: source_segments.len() - 1,
+            found: generated_segments.len() - 1,
+        });
+    }
+    let mut edits = vec![];
+    for (generated_segment, source_segment) in generated_segments.iter().zip(&source_segments) {
+        let generated_lines = joined_code_lines(&generated_segment.code_blocks);
+        let source_lines = joined_code_lines(&source_segment.code_blocks);
+        if generated_lines == source_lines {
+            continue;
+        }
+        let source_spans = source_segment
+            .code_blocks
+            .iter()
+            .filter_map(|(_, _, span)| *span)
+            .collect::<Vec<_>>();
+        match (source_spans.first(), source_spans.last(), generated_lines) {
+            (Some(first), Some(last), Some(lines)) => {
+                let lang = source_segment.code_blocks[0].1;
+                let span = SourceSpan {
+                    bytes: first.bytes.start..last.bytes.end,
+                    lines: first.lines.start..last.lines.end,
+                };
+                edits.push(replace_code_block(source, &span, lang, &lines));
+            }
+            (Some(first), Some(last), None) => {
+                let start = line_start(source, first.bytes.start);
+                let end = line_end(source, last.bytes.end);
+                let end = if source[end..].starts_with('\n') {
+                    end + 1
+                } else {
+                    end
+                };
+                edits.push(SourceEdit {
+                    range: start..end,
+                    replacement: String::new(),
+                });
+            }
+            (_, _, Some(lines)) => {
+                let (fence_start, fence_end) = calc_djot_fences(generated_lang, &lines);
+                let mut code_block = format!("{fence_start}\n");
+                for line in &lines {
+                    code_block.push_str(line);
+                    code_block.push('\n');
+                }
+                code_block.push_str(&fence_end);
+                let edit = match source_segment.anchor {
+                    None => SourceEdit {
+                        range: 0..0,
+                        replacement: format!("{code_block}\n\n"),
+                    },
+                    Some(anchor) => {
+                        let position = line_end(source, anchor.bytes.end);
+                        SourceEdit {
+                            range: position..position,
+                            replacement: format!("\n\n{code_block}"),
+                        }
+                    }
+                };
+                edits.push(edit);
+            }
+            (_, _, None) => {}
+        }
+    }
+    Ok(edits)
+}
+
+/// The code blocks between two prose blocks, along with the span of the
+/// prose block they follow (`None` for code before the first prose block).
+struct CodeSegment<'a> {
+    anchor: Option<&'a SourceSpan>,
+    code_blocks: Vec<(&'a [String], GeneratedLanguage, Option<&'a SourceSpan>)>,
+}
+
+fn split_code_segments(blocks: &[SourceToplevelBlock]) -> Vec<CodeSegment<'_>> {
+    let mut segments = vec![CodeSegment {
+        anchor: None,
+        code_blocks: vec![],
+    }];
+    for block in blocks {
+        match block {
+            SourceToplevelBlock::LiterateBlock { span, .. } => segments.push(CodeSegment {
+                anchor: span.as_ref(),
+                code_blocks: vec![],
+            }),
+            SourceToplevelBlock::VerbatimBlock {
+                lang, lines, span, ..
+            } => {
+                if let Some(segment) = segments.last_mut() {
+                    segment.code_blocks.push((lines, *lang, span.as_ref()));
+                }
+            }
+            SourceToplevelBlock::Preamble { .. }
+            | SourceToplevelBlock::Heading { .. }
+            | SourceToplevelBlock::Postamble { .. } => {}
+        }
+    }
+    segments
+}
+
+/// Joins the lines of adjacent code blocks the way tangling lays them out,
+/// separated by a single blank line.
+fn joined_code_lines(
+    code_blocks: &[(&[String], GeneratedLanguage, Option<&SourceSpan>)],
+) -> Option<Vec<String>> {
+    let mut code_blocks = code_blocks.iter();
+    let (first_lines, _, _) = code_blocks.next()?;
+    let mut joined = first_lines.to_vec();
+    for (lines, _, _) in code_blocks {
+        joined.push(String::new());
+        joined.extend(lines.iter().cloned());
+    }
+    Some(joined)
+}
+
+fn line_start(source: &str, position: usize) -> usize {
+    source[..position].rfind('\n').map_or(0, |pos| pos + 1)
+}
+
+/// Returns the position of the newline ending the line `position` is on,
+/// treating a position right after a newline as still being on that line.
+fn line_end(source: &str, position: usize) -> usize {
+    if source[..position].ends_with('\n') {
+        position - 1
+    } else {
+        source[position..]
+            .find('\n')
+            .map_or(source.len(), |pos| position + pos)
+    }
+}
+
+/// Rewrites the code block at `span`, from its opening fence line through
+/// its closing fence line. Whatever precedes the opening fence on its line
+/// (indentation, block quote or list markers) is kept, and carried over to
+/// the following lines as continuation indentation. The opening fence line
+/// itself is kept as written unless the new code needs a longer fence.
+fn replace_code_block(
+    source: &str,
+    span: &SourceSpan,
+    lang: GeneratedLanguage,
+    lines: &[String],
+) -> SourceEdit {
+    let block_start = line_start(source, span.bytes.start);
+    let end = line_end(source, span.bytes.end);
+    let mut start = block_start;
+    let mut prefix = "";
+    let mut opening_fence = "";
+    for line in source[block_start..end].split_inclusive('\n') {
+        if let Some(fence_offset) = line.find("```") {
+            prefix = &line[..fence_offset];
+            opening_fence = line[fence_offset..].trim_end();
+            break;
+        }
+        start += line.len();
+    }
+    let continuation_prefix: String = prefix
+        .chars()
+        .map(|ch| {
+            if ch == '>' || ch.is_whitespace() {
+                ch
+            } else {
+                ' '
+            }
+        })
+        .collect();
+    let (mut fence_start, mut fence_end) = calc_djot_fences(lang, lines);
+    let opening_fence_len = opening_fence.chars().take_while(|&ch| ch == '`').count();
+    if opening_fence_len >= fence_end.len() {
+        fence_end = opening_fence[..opening_fence_len].to_owned();
+        fence_start = opening_fence.to_owned();
+    }
+    let mut replacement = format!("{prefix}{fence_start}\n");
+    for line in lines {
+        replacement.push_str(&continuation_prefix);
+        replacement.push_str(line);
+        replacement.push('\n');
+    }
+    replacement.push_str(&continuation_prefix);
+    replacement.push_str(&fence_end);
+    SourceEdit {
+        range: start..end,
+        replacement,
+    }
+}
+
 pub(crate) fn analyze_line_groups(
     reader: impl io::Read,
+    lang: GeneratedLanguage,
 ) -> Result<Vec<GeneratedLineGroup>, AnalysisError> {
     let reader = BufReader::new(reader);
     let mut lines = reader.lines().collect::<Result<Vec<_>, _>>()?;
     let mut result_deque = VecDeque::new();
-    let Some(ref postamble @ GeneratedLineGroup::Postamble { source_lang }) = take_postamble(&mut lines) else {
-        return Err(AnalysisError::NoValidPostamble);
+    let Some(ref postamble @ GeneratedLineGroup::Postamble { source_lang, .. }) =
+        take_postamble(&mut lines, lang)
+    else {
+        // A file generated from another kind of source ends in a signature
+        // all the same.
+        let source_name = lines
+            .last()
+            .and_then(|line| GeneratedSignature::parse_source_name(line, lang));
+        if source_name.is_some_and(|name| name != "djot") {
+            return Err(AnalysisError::UnsupportedSourceLanguage);
+        }
+        return Err(AnalysisError::NoValidPostamble {
+            line: lines.len().max(1),
+        });
     };
-    assert!(matches!(source_lang, SourceLanguage::Djot));
+    if !matches!(source_lang, SourceLanguage::Djot) {
+        return Err(AnalysisError::UnsupportedSourceLanguage);
+    }
+    // `tangle::generate_output` separates the postamble from the last group
+    // with a single blank line, and every other group from the previous one
+    // in the same way.
+    if lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
     result_deque.push_back(GeneratedLineGroup::Preamble);
-    result_deque.push_back(GeneratedLineGroup::CodeLineGroup(lines));
+    if !lines.is_empty() {
+        let line_count = lines.len();
+        let mut lines = lines.into_iter().peekable();
+        loop {
+            let (group, is_separated) = take_line_group(&mut lines, line_count, lang)?;
+            result_deque.push_back(group);
+            if !is_separated {
+                break;
+            }
+        }
+    }
     result_deque.push_back(postamble.clone());
 
     Ok(result_deque.into())
 }
 
-fn take_postamble(lines: &mut Vec<String>) -> Option<GeneratedLineGroup> {
-    drop_trailing_empty_lines(lines);
-    let last_line = lines.last()?;
-    if last_line.trim() != GENERATED_SIGNATURE {
-        return None;
+/// Takes the next group of lines up to the blank line separating it from
+/// the following group, returning whether such a separator was found.
+///
+/// A group opened by a block marker extends to the matching end marker
+/// instead, since the code it wraps may contain blank lines itself.
+///
+/// `line_count` is the number of lines `lines` started out with, so that
+/// errors can tell the line they were found on.
+fn take_line_group(
+    lines: &mut Peekable<impl ExactSizeIterator<Item = String>>,
+    line_count: usize,
+    lang: GeneratedLanguage,
+) -> Result<(GeneratedLineGroup, bool), AnalysisError> {
+    let marker = lines
+        .peek()
+        .and_then(|line| BlockMarker::parse_start_line(line, lang));
+    let Some(marker) = marker else {
+        let mut group = vec![];
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                return Ok((GeneratedLineGroup::CodeLineGroup(group), true));
+            }
+            group.push(line);
+        }
+        return Ok((GeneratedLineGroup::CodeLineGroup(group), false));
+    };
+    let marker_line = line_count - lines.len() + 1;
+    lines.next();
+    let mut group = vec![];
+    loop {
+        let Some(line) = lines.next() else {
+            return Err(AnalysisError::UnterminatedBlockMarker {
+                index: marker.index,
+                line: marker_line,
+            });
+        };
+        if marker.is_end_line(&line, lang) {
+            break;
+        }
+        group.push(line);
     }
+    let is_separated = match lines.peek() {
+        Some(line) if line.is_empty() => {
+            lines.next();
+            true
+        }
+        Some(_) => true,
+        None => false,
+    };
+    Ok((
+        GeneratedLineGroup::MarkedCodeLineGroup {
+            marker,
+            lines: group,
+        },
+        is_separated,
+    ))
+}
+
+fn take_postamble(lines: &mut Vec<String>, lang: GeneratedLanguage) -> Option<GeneratedLineGroup> {
+    drop_trailing_empty_lines(lines);
+    let signature = GeneratedSignature::parse_line(lines.last()?, lang)?;
     lines.pop();
     Some(GeneratedLineGroup::Postamble {
         source_lang: SourceLanguage::Djot,
+        signature,
     })
 }
 
@@ -126,19 +619,108 @@ fn drop_trailing_empty_lines(lines: &mut Vec<String>) {
     }
 }
 
+use crate::tangle_and_untangle::module_start_line;
+use crate::tangle_and_untangle::BlockMarker;
+use crate::tangle_and_untangle::DocComment;
 use crate::tangle_and_untangle::GeneratedLanguage;
 use crate::tangle_and_untangle::SourceLanguage;
-use crate::tangle_and_untangle::GENERATED_SIGNATURE;
+use crate::tangle_and_untangle::SourceSpan;
 
 use crate::tangle_and_untangle::GeneratedLineGroup;
+use crate::tangle_and_untangle::GeneratedSignature;
 use crate::tangle_and_untangle::SourceToplevelBlock;
 
 use crate::tangle_and_untangle::AnalysisError;
 use crate::tangle_and_untangle::ConversionError;
 use crate::tangle_and_untangle::GenerationError;
 
+use crate::utils;
+
 use std::{
-    collections::VecDeque,
+    borrow::Cow,
+    collections::{HashSet, VecDeque},
     io::{self, BufRead, BufReader},
+    iter::Peekable,
     mem,
+    ops::Range,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_postamble_is_rejected() {
+        let result = analyze_line_groups("fn main() {}\n".as_bytes(), GeneratedLanguage::Rust);
+        assert!(matches!(
+            result,
+            Err(AnalysisError::NoValidPostamble { line: 1 })
+        ));
+    }
+
+    #[test]
+    fn postamble_of_other_source_language_is_rejected() {
+        let generated = "fn main() {}\n\n// [LITWEB md->rust]\n";
+        let result = analyze_line_groups(generated.as_bytes(), GeneratedLanguage::Rust);
+        assert!(matches!(
+            result,
+            Err(AnalysisError::UnsupportedSourceLanguage)
+        ));
+    }
+
+    #[test]
+    fn unterminated_block_marker_is_rejected() {
+        let generated = "\
+fn helper() {}
+
+// [LITWEB block=3 bytes=10..40 lines=4..7 hash=0123456789abcdef]
+fn main() {}
+
+// [LITWEB djot->rust]
+";
+        let result = analyze_line_groups(generated.as_bytes(), GeneratedLanguage::Rust);
+        assert!(matches!(
+            result,
+            Err(AnalysisError::UnterminatedBlockMarker { index: 3, line: 3 })
+        ));
+    }
+
+    #[test]
+    fn line_groups_without_preamble_are_rejected() {
+        let code = vec!["fn main() {}".to_owned()];
+        let generated = vec![GeneratedLineGroup::CodeLineGroup(code)];
+        let result = convert_line_groups(generated, GeneratedLanguage::Rust);
+        assert!(matches!(result, Err(ConversionError::NoValidPreamble)));
+    }
+
+    #[test]
+    fn line_groups_without_postamble_are_rejected() {
+        let generated = vec![
+            GeneratedLineGroup::Preamble,
+            GeneratedLineGroup::CodeLineGroup(vec!["fn main() {}".into()]),
+        ];
+        let result = convert_line_groups(generated, GeneratedLanguage::Rust);
+        assert!(matches!(result, Err(ConversionError::NoValidPostamble)));
+    }
+
+    #[test]
+    fn doc_comments_are_code_outside_rustdoc_mode() {
+        let generated = "//! Crate docs.\n\nuse std::fmt;\n\n// [LITWEB djot->rust]\n";
+        let groups = analyze_line_groups(generated.as_bytes(), GeneratedLanguage::Rust).unwrap();
+        let blocks = convert_line_groups(groups, GeneratedLanguage::Rust).unwrap();
+        assert!(!blocks
+            .iter()
+            .any(|block| matches!(block, SourceToplevelBlock::LiterateBlock { .. })));
+    }
+
+    #[test]
+    fn doc_comments_are_prose_in_rustdoc_mode() {
+        let generated = "//! Crate docs.\n\nuse std::fmt;\n\n// [LITWEB djot->rust rustdoc]\n";
+        let groups = analyze_line_groups(generated.as_bytes(), GeneratedLanguage::Rust).unwrap();
+        let blocks = convert_line_groups(groups, GeneratedLanguage::Rust).unwrap();
+        assert!(matches!(
+            &blocks[1],
+            SourceToplevelBlock::LiterateBlock { lines, .. } if lines == &["Crate docs."]
+        ));
+    }
+}
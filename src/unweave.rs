@@ -0,0 +1,800 @@
+pub(crate) fn generate_output(
+    mut writer: impl io::Write,
+    lines: Vec<String>,
+) -> Result<(), GenerationError> {
+    for line in lines {
+        writeln!(writer, "{line}")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn convert_markdown_text(markdown: &str) -> Result<Vec<String>, ConversionError> {
+    let options = MarkdownOptions::ENABLE_TABLES
+        | MarkdownOptions::ENABLE_FOOTNOTES
+        | MarkdownOptions::ENABLE_STRIKETHROUGH
+        | MarkdownOptions::ENABLE_TASKLISTS
+        | MarkdownOptions::ENABLE_HEADING_ATTRIBUTES;
+    let mut renderer = DjotRenderer::default();
+    for (event, range) in MarkdownParser::new_ext(markdown, options).into_offset_iter() {
+        let line = markdown[..range.start].matches('\n').count() + 1;
+        renderer.push_event(event, line)?;
+    }
+    renderer.finish()
+}
+
+pub(crate) fn analyze_markdown_text(mut reader: impl io::Read) -> Result<String, AnalysisError> {
+    let mut markdown = String::default();
+    let _ = reader.read_to_string(&mut markdown)?;
+    Ok(markdown)
+}
+
+/// Renders a stream of Markdown events as Djot.
+///
+/// This mirrors the Markdown renderer used for weaving: block containers
+/// contribute line prefixes and inline containers are buffered until their
+/// end event. Markdown constructs without a Djot counterpart are reported as
+/// errors rather than dropped.
+#[derive(Default)]
+struct DjotRenderer<'a> {
+    lines: Vec<String>,
+    blocks: Vec<BlockFrame>,
+    inlines: Vec<InlineFrame<'a>>,
+    html_block: Option<String>,
+    need_blank_line: bool,
+}
+
+struct BlockFrame {
+    kind: BlockKind,
+    first_prefix: String,
+    rest_prefix: String,
+    prefix_used: bool,
+}
+
+enum BlockKind {
+    Blockquote,
+    List {
+        next: Option<u64>,
+        tight: Option<bool>,
+    },
+    ListItem,
+    Footnote,
+    Table(TableBuffer),
+}
+
+struct TableBuffer {
+    alignments: Vec<MarkdownAlignment>,
+    rows: Vec<Vec<String>>,
+}
+
+struct InlineFrame<'a> {
+    tag: MarkdownTag<'a>,
+    implicit: bool,
+    text: String,
+}
+
+impl<'a> DjotRenderer<'a> {
+    fn push_event(&mut self, event: MarkdownEvent<'a>, line: usize) -> Result<(), ConversionError> {
+        if !matches!(event, MarkdownEvent::Html(_)) || !self.inlines.is_empty() {
+            self.flush_html_block();
+        }
+        if let MarkdownEvent::Start(MarkdownTag::CodeBlock(
+            kind @ MarkdownCodeBlockKind::Fenced(info),
+        )) = &event
+        {
+            // Only the language of an info string has a place in Djot.
+            if info.split_whitespace().nth(1).is_some() && !is_evaluation_output(kind) {
+                return Err(ConversionError::UnsupportedMarkdown {
+                    construct: "code block info string beyond the language",
+                    line,
+                });
+            }
+        }
+        match event {
+            MarkdownEvent::Start(tag) => self.start_tag(tag)?,
+            MarkdownEvent::End(tag) => self.end_tag(tag)?,
+            MarkdownEvent::Text(text) => {
+                self.ensure_inline();
+                if matches!(self.inlines.last(), Some(frame) if matches!(frame.tag, MarkdownTag::CodeBlock(_)))
+                {
+                    self.push_inline_raw(&text);
+                } else {
+                    let escaped = escape_djot_text(&text, self.is_in_table_cell());
+                    self.push_inline_raw(&escaped);
+                }
+            }
+            MarkdownEvent::Code(code) => {
+                self.ensure_inline();
+                self.push_inline_raw(&djot_verbatim(&code));
+            }
+            MarkdownEvent::Html(html) => {
+                if self.inlines.is_empty()
+                    && self.html_block.is_none()
+                    && self.push_woven_comment(&html)
+                {
+                    return Ok(());
+                }
+                if self.inlines.is_empty() {
+                    self.html_block
+                        .get_or_insert_with(String::new)
+                        .push_str(&html);
+                } else {
+                    self.push_inline_raw(&djot_inline_html(&html));
+                }
+            }
+            MarkdownEvent::FootnoteReference(label) => {
+                self.ensure_inline();
+                self.push_inline_raw(&format!("[^{label}]"));
+            }
+            MarkdownEvent::SoftBreak => self.push_inline_raw("\n"),
+            MarkdownEvent::HardBreak => {
+                let construct = match self.inlines.first().map(|frame| &frame.tag) {
+                    Some(MarkdownTag::TableCell) => Some("hard line break in a table cell"),
+                    Some(MarkdownTag::Heading(..)) => Some("hard line break in a heading"),
+                    _ => None,
+                };
+                if let Some(construct) = construct {
+                    return Err(ConversionError::UnsupportedMarkdown { construct, line });
+                }
+                self.push_inline_raw("\\\n");
+            }
+            MarkdownEvent::Rule => {
+                self.separate_block();
+                self.push_line("* * *");
+                self.need_blank_line = true;
+            }
+            MarkdownEvent::TaskListMarker(checked) => {
+                // The marker belongs right after the list item's bullet, no
+                // matter whether the item content is wrapped in a paragraph.
+                if let Some(BlockFrame {
+                    kind: BlockKind::ListItem,
+                    first_prefix,
+                    prefix_used: false,
+                    ..
+                }) = self.blocks.last_mut()
+                {
+                    first_prefix.push_str(if checked { "[x] " } else { "[ ] " });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<String>, ConversionError> {
+        self.flush_html_block();
+        if !self.blocks.is_empty() || !self.inlines.is_empty() {
+            return Err(ConversionError::UnbalancedMarkdownTag);
+        }
+        Ok(self.lines)
+    }
+
+    fn start_tag(&mut self, tag: MarkdownTag<'a>) -> Result<(), ConversionError> {
+        match &tag {
+            // Evaluation output is added by weaving, so it is collected only
+            // to be dropped.
+            MarkdownTag::CodeBlock(kind) if is_evaluation_output(kind) => {
+                self.flush_implicit_paragraph();
+                self.push_inline(tag, false);
+            }
+            MarkdownTag::Paragraph | MarkdownTag::CodeBlock(_) => {
+                self.flush_implicit_paragraph();
+                self.resolve_list_tightness(false);
+                self.separate_block();
+                self.push_inline(tag, false);
+            }
+            MarkdownTag::Heading(_, id, classes) => {
+                self.flush_implicit_paragraph();
+                self.resolve_list_tightness(false);
+                self.separate_block();
+                let mut attributes = vec![];
+                if let Some(id) = id {
+                    attributes.push(format!("#{id}"));
+                }
+                attributes.extend(classes.iter().map(|class| format!(".{class}")));
+                if !attributes.is_empty() {
+                    self.push_line(&format!("{{{}}}", attributes.join(" ")));
+                }
+                self.push_inline(tag, false);
+            }
+            MarkdownTag::BlockQuote => {
+                self.flush_implicit_paragraph();
+                self.resolve_list_tightness(false);
+                self.separate_block();
+                self.push_block(BlockKind::Blockquote, "> ", "> ");
+            }
+            MarkdownTag::List(start) => {
+                self.flush_implicit_paragraph();
+                self.resolve_list_tightness(false);
+                self.separate_block();
+                self.push_block(
+                    BlockKind::List {
+                        next: *start,
+                        tight: None,
+                    },
+                    "",
+                    "",
+                );
+            }
+            MarkdownTag::Item => {
+                let Some(BlockFrame {
+                    kind: BlockKind::List { next, tight },
+                    ..
+                }) = self.blocks.last_mut()
+                else {
+                    return Err(ConversionError::UnbalancedMarkdownTag);
+                };
+                let marker = match next {
+                    None => "- ".to_owned(),
+                    Some(next) => {
+                        let marker = format!("{next}. ");
+                        *next += 1;
+                        marker
+                    }
+                };
+                if *tight == Some(true) {
+                    self.need_blank_line = false;
+                } else {
+                    self.separate_block();
+                }
+                let continuation = " ".repeat(marker.len());
+                self.push_block(BlockKind::ListItem, &marker, &continuation);
+            }
+            MarkdownTag::FootnoteDefinition(label) => {
+                self.flush_implicit_paragraph();
+                self.separate_block();
+                self.push_block(BlockKind::Footnote, &format!("[^{label}]: "), "  ");
+            }
+            MarkdownTag::Table(alignments) => {
+                self.flush_implicit_paragraph();
+                self.resolve_list_tightness(false);
+                self.separate_block();
+                let table = TableBuffer {
+                    alignments: alignments.clone(),
+                    rows: vec![],
+                };
+                self.push_block(BlockKind::Table(table), "", "");
+            }
+            MarkdownTag::TableHead | MarkdownTag::TableRow => {
+                self.current_table()?.rows.push(vec![]);
+            }
+            MarkdownTag::TableCell => {
+                self.current_table()?;
+                self.push_inline(tag, false);
+            }
+            MarkdownTag::Emphasis
+            | MarkdownTag::Strong
+            | MarkdownTag::Strikethrough
+            | MarkdownTag::Link(..)
+            | MarkdownTag::Image(..) => {
+                self.ensure_inline();
+                self.push_inline(tag, false);
+            }
+        }
+        Ok(())
+    }
+
+    fn end_tag(&mut self, tag: MarkdownTag<'a>) -> Result<(), ConversionError> {
+        match &tag {
+            MarkdownTag::Paragraph => {
+                let text = self.pop_inline(&tag)?;
+                for line in text.lines() {
+                    self.push_line(&escape_djot_line_start(line));
+                }
+                self.need_blank_line = true;
+            }
+            MarkdownTag::Heading(level, ..) => {
+                let text = self.pop_inline(&tag)?;
+                let marker = "#".repeat(*level as usize);
+                self.push_line(&format!("{marker} {}", text.replace('\n', " ")));
+                self.need_blank_line = true;
+            }
+            MarkdownTag::CodeBlock(kind) if is_evaluation_output(kind) => {
+                self.pop_inline(&tag)?;
+            }
+            MarkdownTag::CodeBlock(kind) => {
+                let text = self.pop_inline(&tag)?;
+                let language = match kind {
+                    MarkdownCodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().unwrap_or_default()
+                    }
+                    MarkdownCodeBlockKind::Indented => "",
+                };
+                self.push_code_block(language, &text);
+                self.need_blank_line = true;
+            }
+            MarkdownTag::Item => {
+                self.flush_implicit_paragraph();
+                self.pop_block()?;
+            }
+            MarkdownTag::BlockQuote | MarkdownTag::List(_) | MarkdownTag::FootnoteDefinition(_) => {
+                self.pop_block()?;
+            }
+            MarkdownTag::Table(_) => {
+                let Some(BlockFrame {
+                    kind: BlockKind::Table(table),
+                    ..
+                }) = self.blocks.pop()
+                else {
+                    return Err(ConversionError::UnbalancedMarkdownTag);
+                };
+                self.render_table(table);
+                self.need_blank_line = true;
+            }
+            MarkdownTag::TableHead | MarkdownTag::TableRow => {}
+            MarkdownTag::TableCell => {
+                let text = self.pop_inline(&tag)?;
+                let row = self
+                    .current_table()?
+                    .rows
+                    .last_mut()
+                    .ok_or(ConversionError::UnbalancedMarkdownTag)?;
+                row.push(text.replace('\n', " "));
+            }
+            MarkdownTag::Emphasis => self.wrap_inline(&tag, "_", "_")?,
+            MarkdownTag::Strong => self.wrap_inline(&tag, "*", "*")?,
+            MarkdownTag::Strikethrough => self.wrap_inline(&tag, "{-", "-}")?,
+            MarkdownTag::Link(link_type, url, title) => {
+                let text = self.pop_inline(&tag)?;
+                let link = match link_type {
+                    MarkdownLinkType::Autolink | MarkdownLinkType::Email => format!("<{url}>"),
+                    _ => format!("[{text}]({url}){}", djot_title_attribute(title)),
+                };
+                self.push_inline_raw(&link);
+            }
+            MarkdownTag::Image(_, url, title) => {
+                let text = self.pop_inline(&tag)?;
+                let title = djot_title_attribute(title);
+                self.push_inline_raw(&format!("![{text}]({url}){title}"));
+            }
+        }
+        Ok(())
+    }
+
+    fn push_block(&mut self, kind: BlockKind, first_prefix: &str, rest_prefix: &str) {
+        self.blocks.push(BlockFrame {
+            kind,
+            first_prefix: first_prefix.to_owned(),
+            rest_prefix: rest_prefix.to_owned(),
+            prefix_used: first_prefix.is_empty(),
+        });
+    }
+
+    fn pop_block(&mut self) -> Result<(), ConversionError> {
+        self.blocks
+            .pop()
+            .ok_or(ConversionError::UnbalancedMarkdownTag)?;
+        self.need_blank_line = true;
+        Ok(())
+    }
+
+    fn push_inline(&mut self, tag: MarkdownTag<'a>, implicit: bool) {
+        self.inlines.push(InlineFrame {
+            tag,
+            implicit,
+            text: String::new(),
+        });
+    }
+
+    fn pop_inline(&mut self, tag: &MarkdownTag<'a>) -> Result<String, ConversionError> {
+        match self.inlines.pop() {
+            Some(frame) if frame.tag == *tag && !frame.implicit => Ok(frame.text),
+            _ => Err(ConversionError::UnbalancedMarkdownTag),
+        }
+    }
+
+    fn wrap_inline(
+        &mut self,
+        tag: &MarkdownTag<'a>,
+        open: &str,
+        close: &str,
+    ) -> Result<(), ConversionError> {
+        let text = self.pop_inline(tag)?;
+        self.push_inline_raw(&format!("{open}{text}{close}"));
+        Ok(())
+    }
+
+    fn push_inline_raw(&mut self, text: &str) {
+        if let Some(frame) = self.inlines.last_mut() {
+            frame.text.push_str(text);
+        }
+    }
+
+    /// Tight list items carry their text without an enclosing paragraph, so
+    /// one is opened implicitly when inline content shows up on its own.
+    fn ensure_inline(&mut self) {
+        if self.inlines.is_empty() {
+            self.resolve_list_tightness(true);
+            self.separate_block();
+            self.push_inline(MarkdownTag::Paragraph, true);
+        }
+    }
+
+    fn flush_implicit_paragraph(&mut self) {
+        if !matches!(self.inlines.last(), Some(frame) if frame.implicit) {
+            return;
+        }
+        let Some(frame) = self.inlines.pop() else {
+            return;
+        };
+        for line in frame.text.lines() {
+            self.push_line(&escape_djot_line_start(line));
+        }
+        self.need_blank_line = true;
+    }
+
+    /// Turns a comment that weaving wrote for block attributes or a div
+    /// fence back into Djot. Such a comment is an HTML block of its own,
+    /// since a comment block ends on the line that closes the comment.
+    fn push_woven_comment(&mut self, html: &str) -> bool {
+        let Some(comment) = html
+            .trim_end()
+            .strip_prefix("<!-- ")
+            .and_then(|comment| comment.strip_suffix(" -->"))
+        else {
+            return false;
+        };
+        if comment.starts_with('{') && comment.ends_with('}') {
+            // The attribute line goes right above the block it belongs to.
+            self.separate_block();
+            self.push_line(comment);
+        } else if comment.starts_with(":::") {
+            self.separate_block();
+            self.push_line(comment);
+            self.need_blank_line = true;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn flush_html_block(&mut self) {
+        let Some(html) = self.html_block.take() else {
+            return;
+        };
+        if html.trim() == WOVEN_SIGNATURE {
+            return;
+        }
+        self.separate_block();
+        self.push_code_block("=html", &html);
+        self.need_blank_line = true;
+    }
+
+    /// Markdown only marks a list as loose by wrapping item contents in
+    /// paragraphs, so the first block seen in the first item decides whether
+    /// the Djot list gets blank lines between its items.
+    fn resolve_list_tightness(&mut self, is_tight_content: bool) {
+        let [.., BlockFrame {
+            kind: BlockKind::List { tight, .. },
+            ..
+        }, BlockFrame {
+            kind: BlockKind::ListItem,
+            ..
+        }] = &mut self.blocks[..]
+        else {
+            return;
+        };
+        if tight.is_none() {
+            *tight = Some(is_tight_content);
+        }
+    }
+
+    fn is_in_table_cell(&self) -> bool {
+        matches!(
+            self.inlines.first().map(|frame| &frame.tag),
+            Some(MarkdownTag::TableCell)
+        )
+    }
+
+    fn current_table(&mut self) -> Result<&mut TableBuffer, ConversionError> {
+        match self.blocks.last_mut() {
+            Some(BlockFrame {
+                kind: BlockKind::Table(table),
+                ..
+            }) => Ok(table),
+            _ => Err(ConversionError::UnbalancedMarkdownTag),
+        }
+    }
+
+    fn separate_block(&mut self) {
+        if mem::take(&mut self.need_blank_line) {
+            self.push_blank_line();
+        }
+    }
+
+    fn push_line(&mut self, content: &str) {
+        let mut line = String::new();
+        for frame in self.blocks.iter_mut() {
+            if mem::replace(&mut frame.prefix_used, true) {
+                line.push_str(&frame.rest_prefix);
+            } else {
+                line.push_str(&frame.first_prefix);
+            }
+        }
+        line.push_str(content);
+        self.lines.push(line.trim_end().to_owned());
+    }
+
+    fn push_blank_line(&mut self) {
+        let mut line = String::new();
+        for frame in self.blocks.iter().filter(|frame| frame.prefix_used) {
+            line.push_str(&frame.rest_prefix);
+        }
+        self.lines.push(line.trim_end().to_owned());
+    }
+
+    fn push_code_block(&mut self, language: &str, text: &str) {
+        let longest_fence = text
+            .lines()
+            .map(|line| {
+                line.trim_start()
+                    .chars()
+                    .take_while(|&ch| ch == '`')
+                    .count()
+            })
+            .max()
+            .unwrap_or(0);
+        let fence = "`".repeat(Ord::max(3, longest_fence + 1));
+        if language.is_empty() {
+            self.push_line(&fence);
+        } else {
+            self.push_line(&format!("{fence} {language}"));
+        }
+        for line in text.lines() {
+            self.push_line(line);
+        }
+        self.push_line(&fence);
+    }
+
+    fn render_table(&mut self, table: TableBuffer) {
+        let column_count = table.alignments.len();
+        let mut rows = table.rows.into_iter();
+        let Some(header) = rows.next() else {
+            return;
+        };
+        self.push_line(&djot_table_row(header, column_count));
+        let delimiters = table
+            .alignments
+            .iter()
+            .map(|alignment| {
+                match alignment {
+                    MarkdownAlignment::None => "---",
+                    MarkdownAlignment::Left => ":--",
+                    MarkdownAlignment::Center => ":-:",
+                    MarkdownAlignment::Right => "--:",
+                }
+                .to_owned()
+            })
+            .collect();
+        self.push_line(&djot_table_row(delimiters, column_count));
+        for row in rows {
+            self.push_line(&djot_table_row(row, column_count));
+        }
+    }
+}
+
+/// Whether a code block holds the output of an evaluated block.
+fn is_evaluation_output(kind: &MarkdownCodeBlockKind) -> bool {
+    match kind {
+        MarkdownCodeBlockKind::Fenced(info) => info
+            .split_whitespace()
+            .skip(1)
+            .any(|word| word == EVALUATION_OUTPUT_TAG),
+        MarkdownCodeBlockKind::Indented => false,
+    }
+}
+
+fn djot_table_row(mut cells: Vec<String>, column_count: usize) -> String {
+    cells.resize(column_count, String::new());
+    let mut row = String::from("|");
+    for cell in cells {
+        row.push(' ');
+        row.push_str(&cell);
+        row.push_str(" |");
+    }
+    row
+}
+
+fn djot_verbatim(text: &str) -> String {
+    let mut longest_run = 0;
+    let mut current_run = 0;
+    for ch in text.chars() {
+        if ch == '`' {
+            current_run += 1;
+            longest_run = Ord::max(longest_run, current_run);
+        } else {
+            current_run = 0;
+        }
+    }
+    let fence = "`".repeat(longest_run + 1);
+    if text.starts_with('`') || text.ends_with('`') {
+        format!("{fence} {text} {fence}")
+    } else {
+        format!("{fence}{text}{fence}")
+    }
+}
+
+fn djot_title_attribute(title: &str) -> String {
+    if title.is_empty() {
+        return String::new();
+    }
+    let title = title.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("{{title=\"{title}\"}}")
+}
+
+/// Maps the inline HTML tags the weaver emits for Djot-only formatting back
+/// to Djot syntax, passing any other inline HTML through as a raw inline.
+fn djot_inline_html(html: &str) -> String {
+    match html {
+        "<sub>" | "</sub>" => "~".to_owned(),
+        "<sup>" | "</sup>" => "^".to_owned(),
+        "<ins>" => "{+".to_owned(),
+        "</ins>" => "+}".to_owned(),
+        "<mark>" => "{=".to_owned(),
+        "</mark>" => "=}".to_owned(),
+        _ => format!("{}{{=html}}", djot_verbatim(html)),
+    }
+}
+
+fn escape_djot_text(text: &str, in_table_cell: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '\\' | '`' | '*' | '_' | '[' | ']' | '{' | '}' | '~' | '^' | '<' => {
+                escaped.push('\\');
+                escaped.push(ch);
+            }
+            '|' if in_table_cell => escaped.push_str("\\|"),
+            '\u{a0}' => escaped.push_str("\\ "),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// Escapes the start of a paragraph line if Djot would otherwise read it as
+/// the start of a heading, block quote, list item, table, div or thematic
+/// break.
+fn escape_djot_line_start(line: &str) -> String {
+    let content = line.trim_start();
+    let indent = &line[..line.len() - content.len()];
+    let followed_by_space = |rest: &str| rest.is_empty() || rest.starts_with(' ');
+    match content.chars().next() {
+        Some('>' | '|') => return format!("{indent}\\{content}"),
+        Some('#') if followed_by_space(content.trim_start_matches('#')) => {
+            return format!("{indent}\\{content}");
+        }
+        Some('-' | '+' | ':') if followed_by_space(&content[1..]) => {
+            return format!("{indent}\\{content}");
+        }
+        Some('-' | ':')
+            if content
+                .chars()
+                .all(|ch| ch == content.as_bytes()[0] as char) =>
+        {
+            return format!("{indent}\\{content}");
+        }
+        _ => {}
+    }
+    // Ordered list markers: `1.`, `a)`, `(iv)` and the like.
+    let marker = content.split(' ').next().unwrap_or_default();
+    let inner = marker.strip_prefix('(').unwrap_or(marker);
+    let Some(delimiter_pos) = inner.find(['.', ')']) else {
+        return line.to_owned();
+    };
+    let (enumerator, delimiter) = inner.split_at(delimiter_pos);
+    let is_enumerator = enumerator.chars().all(|ch| ch.is_ascii_digit())
+        || enumerator.chars().count() == 1 && enumerator.chars().all(|ch| ch.is_ascii_alphabetic())
+        || enumerator.chars().all(|ch| "ivxlcdm".contains(ch))
+        || enumerator.chars().all(|ch| "IVXLCDM".contains(ch));
+    if enumerator.is_empty() || !is_enumerator || delimiter.len() != 1 {
+        return line.to_owned();
+    }
+    let split_at = line.len() - content.len() + (marker.len() - inner.len()) + delimiter_pos;
+    format!("{}\\{}", &line[..split_at], &line[split_at..])
+}
+
+use crate::weave_and_unweave::EVALUATION_OUTPUT_TAG;
+use crate::weave_and_unweave::GENERATED_SIGNATURE as WOVEN_SIGNATURE;
+
+use crate::weave_and_unweave::AnalysisError;
+use crate::weave_and_unweave::ConversionError;
+use crate::weave_and_unweave::GenerationError;
+
+use std::io;
+use std::mem;
+
+use pulldown_cmark::Alignment as MarkdownAlignment;
+use pulldown_cmark::CodeBlockKind as MarkdownCodeBlockKind;
+use pulldown_cmark::Event as MarkdownEvent;
+use pulldown_cmark::LinkType as MarkdownLinkType;
+use pulldown_cmark::Options as MarkdownOptions;
+use pulldown_cmark::Parser as MarkdownParser;
+use pulldown_cmark::Tag as MarkdownTag;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluation_output_is_dropped() {
+        let markdown = "\
+```rust
+println!(\"hi\");
+```
+
+```text litweb-output
+hi
+```
+
+After.
+";
+        let lines = convert_markdown_text(markdown).unwrap();
+        assert_eq!(
+            lines,
+            ["``` rust", "println!(\"hi\");", "```", "", "After."]
+        );
+    }
+
+    #[test]
+    fn info_string_beyond_language_is_rejected() {
+        let markdown = "Text.\n\n```rust title=\"main.rs\"\nfn main() {}\n```\n";
+        assert!(matches!(
+            convert_markdown_text(markdown),
+            Err(ConversionError::UnsupportedMarkdown { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn woven_comments_become_attributes_and_div_fences() {
+        let markdown = "\
+<!-- {chunk=greeting .test} -->
+```rust
+hello();
+```
+
+<!-- {#aside} -->
+<!-- ::: note -->
+
+Text.
+
+<!-- ::: -->
+";
+        let lines = convert_markdown_text(markdown).unwrap();
+        assert_eq!(
+            lines,
+            [
+                "{chunk=greeting .test}",
+                "``` rust",
+                "hello();",
+                "```",
+                "",
+                "{#aside}",
+                "::: note",
+                "",
+                "Text.",
+                "",
+                ":::",
+            ]
+        );
+    }
+
+    #[test]
+    fn block_attributes_survive_weaving_and_unweaving() {
+        let source = "\
+# Greeting
+
+{chunk=greeting .test}
+```rust
+hello();
+```
+
+{file=src/lib.rs}
+```rust
+fn hello() {}
+```
+";
+        let woven = crate::weave::convert_source_text(source, None).unwrap();
+        let unwoven = convert_markdown_text(&woven.join("\n")).unwrap();
+        let rewoven = crate::weave::convert_source_text(&unwoven.join("\n"), None).unwrap();
+        assert_eq!(rewoven, woven);
+    }
+}
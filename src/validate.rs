@@ -0,0 +1,121 @@
+/// Parses the Rust code of a target with `syn`, so that a syntax error is
+/// reported at the line of the code block it comes from instead of being
+/// found by the compiler in the generated file.
+///
+/// An error on a line added by tangling, such as the closing brace of a
+/// module, is reported at the nearest source line before it.
+pub(crate) fn validate_syntax(target: &GeneratedTarget) -> Result<(), ConversionError> {
+    if target.lang != GeneratedLanguage::Rust {
+        return Ok(());
+    }
+    let mut text = String::new();
+    let mut line_origins = vec![];
+    for (line_group, origins) in target.line_groups.iter().zip(&target.line_origins) {
+        let (GeneratedLineGroup::CodeLineGroup(lines)
+        | GeneratedLineGroup::MarkedCodeLineGroup { lines, .. }) = line_group
+        else {
+            continue;
+        };
+        for (index, line) in lines.iter().enumerate() {
+            text.push_str(line);
+            text.push('\n');
+            line_origins.push(origins.get(index).copied().flatten());
+        }
+    }
+    let Err(error) = syn::parse_file(&text) else {
+        return Ok(());
+    };
+    // Lines from `syn` are 1-based and its columns 0-based.
+    let start = error.span().start();
+    let origin = line_origins
+        .get(start.line.wrapping_sub(1))
+        .copied()
+        .flatten();
+    let (line, column) = match origin {
+        Some(origin) => (
+            Some(origin.line),
+            Some(origin.column + (start.column + 1).saturating_sub(origin.generated_column)),
+        ),
+        None => {
+            let preceding = line_origins.iter().take(start.line).rev().flatten().next();
+            (preceding.map(|origin| origin.line), None)
+        }
+    };
+    Err(ConversionError::InvalidRustSyntax {
+        message: error.to_string(),
+        line,
+        column,
+    })
+}
+
+use crate::tangle_and_untangle::ConversionError;
+use crate::tangle_and_untangle::GeneratedLanguage;
+use crate::tangle_and_untangle::GeneratedLineGroup;
+use crate::tangle_and_untangle::GeneratedTarget;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tangle;
+    use crate::TangleOptions;
+
+    fn validate(source: &str, options: &TangleOptions) -> Result<(), ConversionError> {
+        let blocks = tangle::analyze_source_blocks(source.as_bytes()).unwrap();
+        let targets =
+            tangle::convert_source_blocks(blocks, GeneratedLanguage::Rust, "doc.lit.djot", options)
+                .unwrap();
+        validate_syntax(&targets[0])
+    }
+
+    #[test]
+    fn valid_code_passes() {
+        assert!(validate("```rust\nfn main() {}\n```\n", &TangleOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn syntax_error_is_reported_at_its_source_line() {
+        let source = "\
+# Entry point
+
+Prints a greeting.
+
+```rust
+fn main() {
+    let x = ;
+}
+```
+";
+        assert!(matches!(
+            validate(source, &TangleOptions::default()),
+            Err(ConversionError::InvalidRustSyntax {
+                line: Some(7),
+                column: Some(13),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn error_on_added_line_is_reported_at_preceding_source_line() {
+        let source = "\
+{mod=parser}
+## Parser
+
+```rust
+struct Parser
+```
+";
+        let options = TangleOptions {
+            modules: true,
+            ..TangleOptions::default()
+        };
+        assert!(matches!(
+            validate(source, &options),
+            Err(ConversionError::InvalidRustSyntax {
+                line: Some(5),
+                column: None,
+                ..
+            })
+        ));
+    }
+}
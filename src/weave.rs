@@ -0,0 +1,955 @@
+pub(crate) fn generate_output(
+    mut writer: impl io::Write,
+    lines: Vec<String>,
+) -> Result<(), GenerationError> {
+    let has_content = !lines.is_empty();
+    for line in lines {
+        writeln!(writer, "{line}")?;
+    }
+    if has_content {
+        writeln!(writer)?;
+    }
+    writeln!(writer, "{signature}", signature = GENERATED_SIGNATURE)?;
+    Ok(())
+}
+
+/// Renders the source as Markdown. With an evaluator, the output of every
+/// block marked `eval` is added below it.
+pub(crate) fn convert_source_text(
+    source: &str,
+    mut evaluator: Option<&mut Evaluator>,
+) -> Result<Vec<String>, ConversionError> {
+    let mut renderer = MarkdownRenderer::default();
+    // The code read so far of the block being evaluated, and the line of
+    // its opening fence.
+    let mut evaluated_block: Option<(String, usize)> = None;
+    for (event, range) in DjotParser::new(source).into_offset_iter() {
+        let is_code_block_end = matches!(event, DjotEvent::End(DjotContainer::CodeBlock { .. }));
+        match &event {
+            DjotEvent::Start(DjotContainer::CodeBlock { language }, attributes)
+                if evaluator.is_some() && eval::is_evaluated(language, attributes) =>
+            {
+                let fence_offset = source[range.clone()]
+                    .lines()
+                    .position(|line| line.trim_start().starts_with("```"))
+                    .unwrap_or(0);
+                let line = source[..range.start].matches('\n').count() + 1 + fence_offset;
+                evaluated_block = Some((String::new(), line));
+            }
+            DjotEvent::Str(s) => {
+                if let Some((code, _)) = &mut evaluated_block {
+                    code.push_str(s);
+                }
+            }
+            _ => {}
+        }
+        renderer.push_event(event)?;
+        if !is_code_block_end {
+            continue;
+        }
+        if let (Some((code, line)), Some(evaluator)) =
+            (evaluated_block.take(), evaluator.as_deref_mut())
+        {
+            let evaluation = evaluator.evaluate(&code, line)?;
+            renderer.push_evaluation(&evaluation);
+        }
+    }
+    renderer.finish()
+}
+
+pub(crate) fn analyze_source_text(mut reader: impl io::Read) -> Result<String, AnalysisError> {
+    let mut source = String::default();
+    let _ = reader.read_to_string(&mut source)?;
+    Ok(source)
+}
+
+/// Renders a stream of Djot events as CommonMark with GFM extensions.
+///
+/// Block containers contribute line prefixes (`> ` for block quotes, list
+/// markers and their continuation indentation for list items), while inline
+/// containers are buffered until their end event so that constructs like
+/// links and verbatim spans can be assembled from their contents.
+#[derive(Default)]
+struct MarkdownRenderer<'s> {
+    lines: Vec<String>,
+    blocks: Vec<BlockFrame>,
+    inlines: Vec<InlineFrame<'s>>,
+    need_blank_line: bool,
+}
+
+struct BlockFrame {
+    kind: BlockKind,
+    first_prefix: String,
+    rest_prefix: String,
+    prefix_used: bool,
+}
+
+enum BlockKind {
+    Transparent,
+    Blockquote,
+    List { tight: bool, marker: ListMarker },
+    ListItem { tight: bool },
+    Footnote,
+    Table(TableBuffer),
+}
+
+enum ListMarker {
+    Bullet,
+    Task,
+    Ordered { next: u64, delimiter: char },
+}
+
+#[derive(Default)]
+struct TableBuffer {
+    rows: Vec<TableRowBuffer>,
+    caption: Option<String>,
+}
+
+struct TableRowBuffer {
+    head: bool,
+    cells: Vec<(String, DjotAlignment)>,
+}
+
+struct InlineFrame<'s> {
+    container: DjotContainer<'s>,
+    text: String,
+}
+
+impl<'s> MarkdownRenderer<'s> {
+    fn push_event(&mut self, event: DjotEvent<'s>) -> Result<(), ConversionError> {
+        match event {
+            DjotEvent::Start(container, attributes) => {
+                if takes_block_attributes(&container) {
+                    self.push_attributes(&attributes);
+                }
+                self.start_container(container)?
+            }
+            DjotEvent::End(container) => self.end_container(container)?,
+            DjotEvent::Str(s) => {
+                if self.is_in_raw_inline() {
+                    self.push_inline_raw(&s);
+                } else {
+                    let escaped = escape_markdown_text(&s, self.is_in_table_cell());
+                    self.push_inline_raw(&escaped);
+                }
+            }
+            DjotEvent::FootnoteReference(label) => {
+                self.push_inline_raw(&format!("[^{label}]"));
+            }
+            DjotEvent::Symbol(symbol) => self.push_inline_raw(&format!(":{symbol}:")),
+            DjotEvent::LeftSingleQuote => self.push_inline_raw("\u{2018}"),
+            DjotEvent::RightSingleQuote => self.push_inline_raw("\u{2019}"),
+            DjotEvent::LeftDoubleQuote => self.push_inline_raw("\u{201c}"),
+            DjotEvent::RightDoubleQuote => self.push_inline_raw("\u{201d}"),
+            DjotEvent::Ellipsis => self.push_inline_raw("\u{2026}"),
+            DjotEvent::EnDash => self.push_inline_raw("\u{2013}"),
+            DjotEvent::EmDash => self.push_inline_raw("\u{2014}"),
+            DjotEvent::NonBreakingSpace => self.push_inline_raw("&nbsp;"),
+            DjotEvent::Softbreak => self.push_inline_raw("\n"),
+            DjotEvent::Hardbreak => self.push_inline_raw("\\\n"),
+            DjotEvent::Escape | DjotEvent::Blankline => {}
+            DjotEvent::ThematicBreak(attributes) => {
+                self.push_attributes(&attributes);
+                self.separate_block();
+                self.push_line("***");
+                self.need_blank_line = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Vec<String>, ConversionError> {
+        if !self.blocks.is_empty() || !self.inlines.is_empty() {
+            return Err(ConversionError::UnbalancedDjotContainer);
+        }
+        Ok(self.lines)
+    }
+
+    fn start_container(&mut self, container: DjotContainer<'s>) -> Result<(), ConversionError> {
+        match &container {
+            DjotContainer::Blockquote => {
+                self.separate_block();
+                self.push_block(BlockKind::Blockquote, "> ", "> ");
+            }
+            DjotContainer::List { kind, tight } => {
+                self.separate_block();
+                let marker = match kind {
+                    DjotListKind::Unordered => ListMarker::Bullet,
+                    DjotListKind::Task => ListMarker::Task,
+                    DjotListKind::Ordered { style, start, .. } => ListMarker::Ordered {
+                        next: *start,
+                        delimiter: match style {
+                            DjotOrderedListStyle::Paren => ')',
+                            DjotOrderedListStyle::Period | DjotOrderedListStyle::ParenParen => '.',
+                        },
+                    },
+                };
+                self.push_block(
+                    BlockKind::List {
+                        tight: *tight,
+                        marker,
+                    },
+                    "",
+                    "",
+                );
+            }
+            DjotContainer::ListItem | DjotContainer::TaskListItem { .. } => {
+                let checked = match &container {
+                    DjotContainer::TaskListItem { checked } => Some(*checked),
+                    _ => None,
+                };
+                let Some(BlockFrame {
+                    kind: BlockKind::List { tight, marker },
+                    ..
+                }) = self.blocks.last_mut()
+                else {
+                    return Err(ConversionError::UnbalancedDjotContainer);
+                };
+                let tight = *tight;
+                let mut item_marker = match marker {
+                    ListMarker::Bullet | ListMarker::Task => "- ".to_owned(),
+                    ListMarker::Ordered { next, delimiter } => {
+                        let item_marker = format!("{next}{delimiter} ");
+                        *next += 1;
+                        item_marker
+                    }
+                };
+                let continuation = " ".repeat(item_marker.len());
+                if let Some(checked) = checked {
+                    item_marker.push_str(if checked { "[x] " } else { "[ ] " });
+                }
+                if tight {
+                    self.need_blank_line = false;
+                } else {
+                    self.separate_block();
+                }
+                self.push_block(BlockKind::ListItem { tight }, &item_marker, &continuation);
+            }
+            DjotContainer::Footnote { label } => {
+                self.separate_block();
+                self.push_block(BlockKind::Footnote, &format!("[^{label}]: "), "    ");
+            }
+            DjotContainer::Table => {
+                self.separate_block();
+                self.push_block(BlockKind::Table(TableBuffer::default()), "", "");
+            }
+            DjotContainer::TableRow { head } => {
+                let table = self.current_table()?;
+                table.rows.push(TableRowBuffer {
+                    head: *head,
+                    cells: vec![],
+                });
+            }
+            DjotContainer::Div { class } => {
+                let fence = match *class {
+                    "" => ":::".to_owned(),
+                    class => format!("::: {class}"),
+                };
+                self.separate_block();
+                self.push_line(&format!("<!-- {fence} -->"));
+                self.need_blank_line = true;
+                self.push_block(BlockKind::Transparent, "", "");
+            }
+            DjotContainer::Section { .. }
+            | DjotContainer::DescriptionList
+            | DjotContainer::DescriptionDetails => {
+                self.push_block(BlockKind::Transparent, "", "");
+            }
+            DjotContainer::Paragraph
+            | DjotContainer::Heading { .. }
+            | DjotContainer::DescriptionTerm
+            | DjotContainer::LinkDefinition { .. }
+            | DjotContainer::RawBlock { .. }
+            | DjotContainer::CodeBlock { .. } => {
+                self.separate_block();
+                self.push_inline(container);
+            }
+            DjotContainer::TableCell { .. } | DjotContainer::Caption => {
+                self.current_table()?;
+                self.push_inline(container);
+            }
+            DjotContainer::Span
+            | DjotContainer::Link(_, _)
+            | DjotContainer::Image(_, _)
+            | DjotContainer::Verbatim
+            | DjotContainer::Math { .. }
+            | DjotContainer::RawInline { .. }
+            | DjotContainer::Subscript
+            | DjotContainer::Superscript
+            | DjotContainer::Insert
+            | DjotContainer::Delete
+            | DjotContainer::Strong
+            | DjotContainer::Emphasis
+            | DjotContainer::Mark => {
+                if self.inlines.is_empty() {
+                    return Err(ConversionError::UnbalancedDjotContainer);
+                }
+                self.push_inline(container);
+            }
+        }
+        Ok(())
+    }
+
+    fn end_container(&mut self, container: DjotContainer<'s>) -> Result<(), ConversionError> {
+        match &container {
+            DjotContainer::Blockquote
+            | DjotContainer::List { .. }
+            | DjotContainer::ListItem
+            | DjotContainer::TaskListItem { .. }
+            | DjotContainer::Footnote { .. }
+            | DjotContainer::Section { .. }
+            | DjotContainer::DescriptionList
+            | DjotContainer::DescriptionDetails => {
+                self.blocks
+                    .pop()
+                    .ok_or(ConversionError::UnbalancedDjotContainer)?;
+                self.need_blank_line = true;
+            }
+            DjotContainer::Div { .. } => {
+                self.blocks
+                    .pop()
+                    .ok_or(ConversionError::UnbalancedDjotContainer)?;
+                self.separate_block();
+                self.push_line("<!-- ::: -->");
+                self.need_blank_line = true;
+            }
+            DjotContainer::Table => {
+                let Some(BlockFrame {
+                    kind: BlockKind::Table(table),
+                    ..
+                }) = self.blocks.pop()
+                else {
+                    return Err(ConversionError::UnbalancedDjotContainer);
+                };
+                self.render_table(table);
+                self.need_blank_line = true;
+            }
+            DjotContainer::TableRow { .. } => {}
+            DjotContainer::TableCell { alignment, .. } => {
+                let text = self.pop_inline(&container)?;
+                let table = self.current_table()?;
+                let row = table
+                    .rows
+                    .last_mut()
+                    .ok_or(ConversionError::UnbalancedDjotContainer)?;
+                row.cells.push((text.replace('\n', " "), *alignment));
+            }
+            DjotContainer::Caption => {
+                let text = self.pop_inline(&container)?;
+                self.current_table()?.caption = Some(text);
+            }
+            DjotContainer::Paragraph => {
+                let text = self.pop_inline(&container)?;
+                for line in text.lines() {
+                    self.push_line(&escape_markdown_line_start(line));
+                }
+                self.need_blank_line = true;
+            }
+            DjotContainer::Heading { level, .. } => {
+                let text = self.pop_inline(&container)?;
+                let marker = "#".repeat(usize::from(*level).clamp(1, 6));
+                self.push_line(&format!("{marker} {}", text.replace('\n', " ")));
+                self.need_blank_line = true;
+            }
+            DjotContainer::DescriptionTerm => {
+                let text = self.pop_inline(&container)?;
+                self.push_line(&format!("**{}**", text.replace('\n', " ")));
+                self.need_blank_line = true;
+            }
+            DjotContainer::LinkDefinition { label } => {
+                let text = self.pop_inline(&container)?;
+                self.push_line(&format!("[{label}]: {}", text.trim()));
+                self.need_blank_line = true;
+            }
+            DjotContainer::RawBlock { format } => {
+                let text = self.pop_inline(&container)?;
+                if *format == "html" {
+                    for line in text.lines() {
+                        self.push_line(line);
+                    }
+                } else {
+                    self.push_code_block(format, &text);
+                }
+                self.need_blank_line = true;
+            }
+            DjotContainer::CodeBlock { language } => {
+                let text = self.pop_inline(&container)?;
+                self.push_code_block(language, &text);
+                self.need_blank_line = true;
+            }
+            DjotContainer::Span => {
+                let text = self.pop_inline(&container)?;
+                self.push_inline_raw(&text);
+            }
+            DjotContainer::Link(url, link_type) => {
+                let text = self.pop_inline(&container)?;
+                let link = match link_type {
+                    DjotLinkType::AutoLink | DjotLinkType::Email => format!("<{url}>"),
+                    DjotLinkType::Span(_) => {
+                        format!("[{text}]({})", markdown_link_destination(url))
+                    }
+                };
+                self.push_inline_raw(&link);
+            }
+            DjotContainer::Image(src, _) => {
+                let text = self.pop_inline(&container)?;
+                self.push_inline_raw(&format!("![{text}]({})", markdown_link_destination(src)));
+            }
+            DjotContainer::Verbatim => {
+                let text = self.pop_inline(&container)?;
+                self.push_inline_raw(&markdown_code_span(&text));
+            }
+            DjotContainer::Math { display } => {
+                let text = self.pop_inline(&container)?;
+                let delimiter = if *display { "$$" } else { "$" };
+                self.push_inline_raw(&format!("{delimiter}{text}{delimiter}"));
+            }
+            DjotContainer::RawInline { format } => {
+                let text = self.pop_inline(&container)?;
+                if *format == "html" {
+                    self.push_inline_raw(&text);
+                }
+            }
+            DjotContainer::Subscript => self.wrap_inline(&container, "<sub>", "</sub>")?,
+            DjotContainer::Superscript => self.wrap_inline(&container, "<sup>", "</sup>")?,
+            DjotContainer::Insert => self.wrap_inline(&container, "<ins>", "</ins>")?,
+            DjotContainer::Delete => self.wrap_inline(&container, "~~", "~~")?,
+            DjotContainer::Strong => self.wrap_inline(&container, "**", "**")?,
+            DjotContainer::Emphasis => self.wrap_inline(&container, "*", "*")?,
+            DjotContainer::Mark => self.wrap_inline(&container, "<mark>", "</mark>")?,
+        }
+        Ok(())
+    }
+
+    fn push_block(&mut self, kind: BlockKind, first_prefix: &str, rest_prefix: &str) {
+        self.blocks.push(BlockFrame {
+            kind,
+            first_prefix: first_prefix.to_owned(),
+            rest_prefix: rest_prefix.to_owned(),
+            prefix_used: first_prefix.is_empty(),
+        });
+    }
+
+    fn push_inline(&mut self, container: DjotContainer<'s>) {
+        self.inlines.push(InlineFrame {
+            container,
+            text: String::new(),
+        });
+    }
+
+    fn pop_inline(&mut self, container: &DjotContainer<'s>) -> Result<String, ConversionError> {
+        match self.inlines.pop() {
+            Some(frame) if frame.container == *container => Ok(frame.text),
+            _ => Err(ConversionError::UnbalancedDjotContainer),
+        }
+    }
+
+    fn wrap_inline(
+        &mut self,
+        container: &DjotContainer<'s>,
+        open: &str,
+        close: &str,
+    ) -> Result<(), ConversionError> {
+        let text = self.pop_inline(container)?;
+        self.push_inline_raw(&format!("{open}{text}{close}"));
+        Ok(())
+    }
+
+    fn push_inline_raw(&mut self, text: &str) {
+        if let Some(frame) = self.inlines.last_mut() {
+            frame.text.push_str(text);
+        }
+    }
+
+    fn is_in_raw_inline(&self) -> bool {
+        matches!(
+            self.inlines.last().map(|frame| &frame.container),
+            Some(
+                DjotContainer::Verbatim
+                    | DjotContainer::Math { .. }
+                    | DjotContainer::RawInline { .. }
+                    | DjotContainer::RawBlock { .. }
+                    | DjotContainer::CodeBlock { .. }
+                    | DjotContainer::LinkDefinition { .. }
+            )
+        )
+    }
+
+    fn is_in_table_cell(&self) -> bool {
+        matches!(
+            self.inlines.first().map(|frame| &frame.container),
+            Some(DjotContainer::TableCell { .. })
+        )
+    }
+
+    fn current_table(&mut self) -> Result<&mut TableBuffer, ConversionError> {
+        match self.blocks.last_mut() {
+            Some(BlockFrame {
+                kind: BlockKind::Table(table),
+                ..
+            }) => Ok(table),
+            _ => Err(ConversionError::UnbalancedDjotContainer),
+        }
+    }
+
+    fn separate_block(&mut self) {
+        let is_in_tight_list_item = matches!(
+            self.blocks.last(),
+            Some(BlockFrame {
+                kind: BlockKind::ListItem { tight: true },
+                ..
+            })
+        );
+        if mem::take(&mut self.need_blank_line) && !is_in_tight_list_item {
+            self.push_blank_line();
+        }
+    }
+
+    fn push_line(&mut self, content: &str) {
+        let mut line = String::new();
+        for frame in self.blocks.iter_mut() {
+            if mem::replace(&mut frame.prefix_used, true) {
+                line.push_str(&frame.rest_prefix);
+            } else {
+                line.push_str(&frame.first_prefix);
+            }
+        }
+        line.push_str(content);
+        self.lines.push(line.trim_end().to_owned());
+    }
+
+    fn push_blank_line(&mut self) {
+        let mut line = String::new();
+        for frame in self.blocks.iter().filter(|frame| frame.prefix_used) {
+            line.push_str(&frame.rest_prefix);
+        }
+        self.lines.push(line.trim_end().to_owned());
+    }
+
+    /// Writes the attributes of a block as an HTML comment right above it,
+    /// where they stay out of the rendered Markdown and unweaving finds
+    /// them again.
+    fn push_attributes(&mut self, attributes: &DjotAttributes) {
+        if attributes.is_empty() {
+            return;
+        }
+        self.separate_block();
+        self.push_line(&format!("<!-- {} -->", djot_attributes(attributes)));
+    }
+
+    /// Adds what an evaluated block printed below it, as a code block for
+    /// each of its standard output and standard error that is not empty.
+    /// The blocks are tagged so that unweaving leaves them out.
+    fn push_evaluation(&mut self, evaluation: &Evaluation) {
+        for output in [&evaluation.stdout, &evaluation.stderr] {
+            if output.trim().is_empty() {
+                continue;
+            }
+            self.separate_block();
+            self.push_code_block(&format!("text {EVALUATION_OUTPUT_TAG}"), output.trim_end());
+            self.need_blank_line = true;
+        }
+    }
+
+    fn push_code_block(&mut self, language: &str, text: &str) {
+        let longest_fence = text
+            .lines()
+            .map(|line| {
+                line.trim_start()
+                    .chars()
+                    .take_while(|&ch| ch == '`')
+                    .count()
+            })
+            .max()
+            .unwrap_or(0);
+        let fence = "`".repeat(Ord::max(3, longest_fence + 1));
+        self.push_line(&format!("{fence}{language}"));
+        for line in text.lines() {
+            self.push_line(line);
+        }
+        self.push_line(&fence);
+    }
+
+    fn render_table(&mut self, table: TableBuffer) {
+        let column_count = table
+            .rows
+            .iter()
+            .map(|row| row.cells.len())
+            .max()
+            .unwrap_or(0);
+        if column_count == 0 {
+            return;
+        }
+        let mut rows = table.rows.into_iter().peekable();
+        let header = match rows.next_if(|row| row.head) {
+            Some(row) => row.cells,
+            None => vec![],
+        };
+        let mut alignments = vec![DjotAlignment::Unspecified; column_count];
+        for (column, (_, alignment)) in header.iter().enumerate() {
+            alignments[column] = *alignment;
+        }
+        let header_texts = header.into_iter().map(|(text, _)| text).collect();
+        self.push_line(&markdown_table_row(header_texts, column_count));
+        let delimiters = alignments
+            .iter()
+            .map(|alignment| {
+                match alignment {
+                    DjotAlignment::Unspecified => "---",
+                    DjotAlignment::Left => ":--",
+                    DjotAlignment::Center => ":-:",
+                    DjotAlignment::Right => "--:",
+                }
+                .to_owned()
+            })
+            .collect();
+        self.push_line(&markdown_table_row(delimiters, column_count));
+        for row in rows {
+            let texts = row.cells.into_iter().map(|(text, _)| text).collect();
+            self.push_line(&markdown_table_row(texts, column_count));
+        }
+        if let Some(caption) = table.caption {
+            self.push_blank_line();
+            for line in caption.lines() {
+                self.push_line(&escape_markdown_line_start(line));
+            }
+        }
+    }
+}
+
+/// Whether attributes given to a container belong to a block that can be
+/// preceded by an attribute line in Djot.
+fn takes_block_attributes(container: &DjotContainer) -> bool {
+    matches!(
+        container,
+        DjotContainer::Blockquote
+            | DjotContainer::List { .. }
+            | DjotContainer::DescriptionList
+            | DjotContainer::Footnote { .. }
+            | DjotContainer::Table
+            | DjotContainer::Section { .. }
+            | DjotContainer::Div { .. }
+            | DjotContainer::Paragraph
+            | DjotContainer::Heading { .. }
+            | DjotContainer::LinkDefinition { .. }
+            | DjotContainer::RawBlock { .. }
+            | DjotContainer::CodeBlock { .. }
+    )
+}
+
+/// Writes attributes in Djot syntax, e.g. `{#intro .note chunk=greeting}`.
+/// Values other than plain words are quoted, with `>` escaped so that the
+/// result never closes the HTML comment it is written into.
+fn djot_attributes(attributes: &DjotAttributes) -> String {
+    let mut parts = vec![];
+    for (key, value) in attributes.iter() {
+        let value = value.to_string();
+        let is_word = !value.is_empty()
+            && value
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '_' | ':' | '-'));
+        match key {
+            "id" => parts.push(format!("#{value}")),
+            "class" => parts.extend(value.split_whitespace().map(|class| format!(".{class}"))),
+            _ if is_word => parts.push(format!("{key}={value}")),
+            _ => {
+                let mut quoted = String::with_capacity(value.len());
+                for ch in value.chars() {
+                    if matches!(ch, '"' | '\\' | '>') {
+                        quoted.push('\\');
+                    }
+                    quoted.push(ch);
+                }
+                parts.push(format!("{key}=\"{quoted}\""));
+            }
+        }
+    }
+    format!("{{{}}}", parts.join(" "))
+}
+
+fn markdown_table_row(mut cells: Vec<String>, column_count: usize) -> String {
+    cells.resize(column_count, String::new());
+    let mut row = String::from("|");
+    for cell in cells {
+        row.push(' ');
+        row.push_str(&cell);
+        row.push_str(" |");
+    }
+    row
+}
+
+fn markdown_code_span(text: &str) -> String {
+    let mut longest_run = 0;
+    let mut current_run = 0;
+    for ch in text.chars() {
+        if ch == '`' {
+            current_run += 1;
+            longest_run = Ord::max(longest_run, current_run);
+        } else {
+            current_run = 0;
+        }
+    }
+    let fence = "`".repeat(longest_run + 1);
+    if text.starts_with('`') || text.ends_with('`') {
+        format!("{fence} {text} {fence}")
+    } else {
+        format!("{fence}{text}{fence}")
+    }
+}
+
+fn markdown_link_destination(url: &str) -> String {
+    if url.contains(|ch: char| ch.is_whitespace() || ch == '(' || ch == ')') {
+        format!("<{url}>")
+    } else {
+        url.to_owned()
+    }
+}
+
+fn escape_markdown_text(text: &str, in_table_cell: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let needs_escape = match ch {
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '~' | '$' => true,
+            '|' => in_table_cell,
+            '&' => {
+                matches!(chars.peek(), Some(next) if next.is_ascii_alphanumeric() || *next == '#')
+            }
+            _ => false,
+        };
+        if needs_escape {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+/// Escapes the first character of a paragraph line if Markdown would
+/// otherwise read it as the start of a heading, block quote, list item or
+/// setext underline.
+fn escape_markdown_line_start(line: &str) -> String {
+    let content = line.trim_start();
+    let indent = &line[..line.len() - content.len()];
+    let followed_by_space = |rest: &str| rest.is_empty() || rest.starts_with(' ');
+    let digits = content.chars().take_while(char::is_ascii_digit).count();
+    let needs_escape = match content.chars().next() {
+        Some('>') => true,
+        Some('#') => followed_by_space(content.trim_start_matches('#')),
+        Some('-' | '+') => followed_by_space(&content[1..]) || content.chars().all(|ch| ch == '-'),
+        Some('=') => content.chars().all(|ch| ch == '='),
+        Some(_) if digits > 0 => {
+            let rest = &content[digits..];
+            (rest.starts_with('.') || rest.starts_with(')')) && followed_by_space(&rest[1..])
+        }
+        _ => false,
+    };
+    if !needs_escape {
+        return line.to_owned();
+    }
+    if digits > 0 {
+        format!("{indent}{}\\{}", &content[..digits], &content[digits..])
+    } else {
+        format!("{indent}\\{content}")
+    }
+}
+
+use crate::eval;
+use crate::eval::Evaluation;
+use crate::eval::Evaluator;
+use crate::weave_and_unweave::EVALUATION_OUTPUT_TAG;
+use crate::weave_and_unweave::GENERATED_SIGNATURE;
+
+use crate::weave_and_unweave::AnalysisError;
+use crate::weave_and_unweave::ConversionError;
+use crate::weave_and_unweave::GenerationError;
+
+use std::io;
+use std::mem;
+
+use jotdown::Alignment as DjotAlignment;
+use jotdown::Attributes as DjotAttributes;
+use jotdown::Container as DjotContainer;
+use jotdown::Event as DjotEvent;
+use jotdown::LinkType as DjotLinkType;
+use jotdown::ListKind as DjotListKind;
+use jotdown::OrderedListStyle as DjotOrderedListStyle;
+use jotdown::Parser as DjotParser;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weave(source: &str) -> Vec<String> {
+        convert_source_text(source, None).unwrap()
+    }
+
+    fn render(events: Vec<DjotEvent>) -> Vec<String> {
+        let mut renderer = MarkdownRenderer::default();
+        for event in events {
+            renderer.push_event(event).unwrap();
+        }
+        renderer.finish().unwrap()
+    }
+
+    fn start(container: DjotContainer) -> DjotEvent {
+        DjotEvent::Start(container, DjotAttributes::default())
+    }
+
+    #[test]
+    fn headings_and_paragraphs_are_rendered() {
+        let source = "# Title\n\nFirst line\nsecond line.\n\n### Details\n";
+        assert_eq!(
+            weave(source),
+            [
+                "# Title",
+                "",
+                "First line",
+                "second line.",
+                "",
+                "### Details"
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown_punctuation_in_text_is_escaped() {
+        assert_eq!(
+            weave("Costs $5 when a < b.\n"),
+            ["Costs \\$5 when a \\< b."]
+        );
+    }
+
+    #[test]
+    fn paragraph_line_that_reads_as_markdown_block_is_escaped() {
+        let paragraph = DjotContainer::Paragraph;
+        let lines = render(vec![
+            start(paragraph.clone()),
+            DjotEvent::Str("# not a heading".into()),
+            DjotEvent::Softbreak,
+            DjotEvent::Str("1. not a list".into()),
+            DjotEvent::End(paragraph),
+        ]);
+        assert_eq!(lines, ["\\# not a heading", "1\\. not a list"]);
+    }
+
+    #[test]
+    fn code_block_fence_outgrows_its_contents() {
+        let code_block = DjotContainer::CodeBlock { language: "djot" };
+        let lines = render(vec![
+            start(code_block.clone()),
+            DjotEvent::Str("```rust\nfn main() {}\n```\n".into()),
+            DjotEvent::End(code_block),
+        ]);
+        assert_eq!(
+            lines,
+            ["````djot", "```rust", "fn main() {}", "```", "````"]
+        );
+    }
+
+    #[test]
+    fn block_attributes_are_kept_in_comments() {
+        let source = "\
+{chunk=greeting .test}
+```rust
+hello();
+```
+
+{file=src/lib.rs}
+```rust
+fn hello() {}
+```
+";
+        assert_eq!(
+            weave(source),
+            [
+                "<!-- {chunk=greeting .test} -->",
+                "```rust",
+                "hello();",
+                "```",
+                "",
+                "<!-- {file=\"src/lib.rs\"} -->",
+                "```rust",
+                "fn hello() {}",
+                "```",
+            ]
+        );
+    }
+
+    #[test]
+    fn div_fences_are_kept_in_comments() {
+        assert_eq!(
+            weave("::: note\nText.\n\n:::\n"),
+            ["<!-- ::: note -->", "", "Text.", "", "<!-- ::: -->"]
+        );
+    }
+
+    #[test]
+    fn lists_are_rendered() {
+        let list = |kind| DjotContainer::List { kind, tight: true };
+        let item = |text| {
+            vec![
+                start(DjotContainer::ListItem),
+                start(DjotContainer::Paragraph),
+                DjotEvent::Str(text),
+                DjotEvent::End(DjotContainer::Paragraph),
+                DjotEvent::End(DjotContainer::ListItem),
+            ]
+        };
+        let ordered = list(DjotListKind::Ordered {
+            numbering: jotdown::OrderedListNumbering::Decimal,
+            style: DjotOrderedListStyle::Paren,
+            start: 3,
+        });
+        let mut events = vec![start(list(DjotListKind::Unordered))];
+        events.extend(item("one".into()));
+        events.extend(item("two".into()));
+        events.push(DjotEvent::End(list(DjotListKind::Unordered)));
+        events.push(start(ordered.clone()));
+        events.extend(item("three".into()));
+        events.extend(item("four".into()));
+        events.push(DjotEvent::End(ordered));
+        assert_eq!(
+            render(events),
+            ["- one", "- two", "", "3) three", "4) four"]
+        );
+    }
+
+    #[test]
+    fn tables_are_rendered() {
+        let cell = |alignment, head, text| {
+            let cell = DjotContainer::TableCell { alignment, head };
+            vec![
+                start(cell.clone()),
+                DjotEvent::Str(text),
+                DjotEvent::End(cell),
+            ]
+        };
+        let row = |head, cells: Vec<Vec<DjotEvent<'static>>>| {
+            let mut events = vec![start(DjotContainer::TableRow { head })];
+            events.extend(cells.into_iter().flatten());
+            events.push(DjotEvent::End(DjotContainer::TableRow { head }));
+            events
+        };
+        let mut events = vec![start(DjotContainer::Table)];
+        events.extend(row(
+            true,
+            vec![
+                cell(DjotAlignment::Left, true, "Name".into()),
+                cell(DjotAlignment::Right, true, "Size".into()),
+            ],
+        ));
+        events.extend(row(
+            false,
+            vec![
+                cell(DjotAlignment::Left, false, "a|b".into()),
+                cell(DjotAlignment::Right, false, "2".into()),
+            ],
+        ));
+        events.push(DjotEvent::End(DjotContainer::Table));
+        assert_eq!(
+            render(events),
+            ["| Name | Size |", "| :-- | --: |", "| a\\|b | 2 |"]
+        );
+    }
+}
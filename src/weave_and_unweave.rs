@@ -0,0 +1,37 @@
+pub(crate) const GENERATED_SIGNATURE: &str = "<!-- [LITWEB djot->markdown] -->";
+
+/// Follows the language in the info string of the code blocks that hold
+/// the output of evaluated blocks, e.g. ```` ```text litweb-output ````, so
+/// that unweaving can tell them from blocks of the document.
+pub(crate) const EVALUATION_OUTPUT_TAG: &str = "litweb-output";
+
+#[derive(Error, Debug)]
+pub enum GenerationError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ConversionError {
+    #[error("Unbalanced Djot container event occurred.")]
+    UnbalancedDjotContainer,
+    #[error("Unbalanced Markdown tag event occurred.")]
+    UnbalancedMarkdownTag,
+    #[error("Markdown construct cannot be translated to Djot: {construct} (line {line})")]
+    UnsupportedMarkdown {
+        construct: &'static str,
+        line: usize,
+    },
+    #[error(transparent)]
+    Eval(#[from] EvalError),
+}
+
+#[derive(Error, Debug)]
+pub enum AnalysisError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+use crate::eval::EvalError;
+use std::io;
+use thiserror::Error;